@@ -0,0 +1,3 @@
+-- Whether the highlighting language was detected from the contents of the
+-- paste, rather than given by the client.
+ALTER TABLE shares ADD COLUMN language_detected BOOLEAN NOT NULL DEFAULT FALSE;
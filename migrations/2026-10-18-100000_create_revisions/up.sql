@@ -0,0 +1,9 @@
+CREATE TABLE revisions (
+    share_name VARCHAR(255) NOT NULL    -- The share this is a previous version of.
+        REFERENCES shares (name) ON DELETE CASCADE,
+    number INTEGER NOT NULL,            -- Counts up from 1 for each share.
+    replaced TIMESTAMP NOT NULL,        -- When this version was superseded.
+    language VARCHAR(31),               -- Syntax highlighting hint for pastes.
+    mime_type VARCHAR(127),             -- MIME type for files.
+    PRIMARY KEY (share_name, number)
+)
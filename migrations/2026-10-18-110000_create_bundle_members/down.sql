@@ -0,0 +1 @@
+DROP TABLE bundle_members;
@@ -0,0 +1,10 @@
+CREATE TABLE bundle_members (
+    share_name VARCHAR(255) NOT NULL    -- The bundle this file is part of.
+        REFERENCES shares (name) ON DELETE CASCADE,
+    position INTEGER NOT NULL,          -- Order within the bundle, also used as a local file name.
+    path VARCHAR(1024) NOT NULL,        -- Path of the file within the bundle.
+    mime_type VARCHAR(127) NOT NULL,    -- MIME type of the file.
+    size BIGINT NOT NULL,               -- Size of the file in bytes.
+    PRIMARY KEY (share_name, position),
+    UNIQUE (share_name, path)
+)
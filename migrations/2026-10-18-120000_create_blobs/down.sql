@@ -0,0 +1,4 @@
+ALTER TABLE bundle_members DROP COLUMN blob;
+ALTER TABLE revisions DROP COLUMN blob;
+ALTER TABLE shares DROP COLUMN blob;
+DROP TABLE blobs;
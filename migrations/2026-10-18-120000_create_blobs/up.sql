@@ -0,0 +1,13 @@
+CREATE TABLE blobs (
+    hash VARCHAR(64) PRIMARY KEY,   -- SHA-256 of the contents, also used as a local file name.
+    size BIGINT NOT NULL,           -- Size of the contents in bytes.
+    refs INTEGER NOT NULL           -- Number of shares, revisions and bundle members using it.
+);
+
+-- Existing rows are given blobs when the server starts, until then the blob of
+-- a share is null and the blob of a revision or bundle member is empty.
+ALTER TABLE shares ADD COLUMN blob VARCHAR(64);
+ALTER TABLE revisions ADD COLUMN blob VARCHAR(64) NOT NULL DEFAULT '';
+ALTER TABLE revisions ALTER COLUMN blob DROP DEFAULT;
+ALTER TABLE bundle_members ADD COLUMN blob VARCHAR(64) NOT NULL DEFAULT '';
+ALTER TABLE bundle_members ALTER COLUMN blob DROP DEFAULT;
@@ -0,0 +1,2 @@
+-- ID of the key the blob is encrypted with, or null if it is not encrypted.
+ALTER TABLE blobs ADD COLUMN key_id VARCHAR(255);
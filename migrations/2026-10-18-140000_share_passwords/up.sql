@@ -0,0 +1,2 @@
+-- Argon2 hash of the password needed to access the share, if any.
+ALTER TABLE shares ADD COLUMN password VARCHAR(255);
@@ -0,0 +1,2 @@
+-- 0 for public, 1 for unlisted and 2 for private.
+ALTER TABLE shares ADD COLUMN visibility SMALLINT NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+ALTER TABLE revisions DROP COLUMN encryption;
+ALTER TABLE shares DROP COLUMN encryption;
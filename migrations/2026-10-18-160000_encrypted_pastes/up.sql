@@ -0,0 +1,3 @@
+-- Parameters for decrypting end-to-end encrypted pastes, such as the cipher.
+ALTER TABLE shares ADD COLUMN encryption VARCHAR;
+ALTER TABLE revisions ADD COLUMN encryption VARCHAR;
@@ -0,0 +1,3 @@
+-- Whether browsers are shown a preview page before following a link, or NULL
+-- to use the server's default.
+ALTER TABLE shares ADD COLUMN link_preview BOOLEAN;
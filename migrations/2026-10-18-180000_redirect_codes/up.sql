@@ -0,0 +1,3 @@
+-- The HTTP status code to redirect to a link with, or NULL to use the
+-- server's default.
+ALTER TABLE shares ADD COLUMN redirect_code SMALLINT;
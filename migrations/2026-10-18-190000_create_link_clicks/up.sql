@@ -0,0 +1,12 @@
+-- Clicks on link shares, counted per day and by where they came from. Unknown
+-- values are stored as empty strings so that they can be part of the key.
+CREATE TABLE link_clicks (
+    share_name VARCHAR(255) NOT NULL        -- The link which was clicked.
+        REFERENCES shares (name) ON DELETE CASCADE,
+    day DATE NOT NULL,                      -- The day of the clicks, in UTC.
+    referrer_host VARCHAR(255) NOT NULL,    -- Host of the page the link was on.
+    user_agent VARCHAR(31) NOT NULL,        -- Browser family, such as "Firefox".
+    country VARCHAR(2) NOT NULL,            -- ISO country code, from a GeoIP database.
+    clicks BIGINT NOT NULL,                 -- Number of clicks.
+    PRIMARY KEY (share_name, day, referrer_host, user_agent, country)
+);
@@ -1,6 +1,8 @@
 //! Tools for describing the features that the server supports.
 use crate::auth::Auth;
 use crate::config::{Config, Permission};
+use crate::mime::MimePattern;
+use crate::render::Renderer;
 use rocket::response::status;
 use serde::Serialize;
 
@@ -21,34 +23,49 @@ pub struct Abilities {
     pub create_paste: bool,
     /// Whether you can create a link with your current password.
     pub create_link: bool,
+    /// Whether you can create a bundle of files with your current password.
+    pub create_bundle: bool,
     /// Whether you can update a share having created it.
     pub update_own: bool,
     /// Whether you can update any share with your current password.
     pub update_any: bool,
+    /// Whether you can read private shares you didn't create.
+    pub read_private: bool,
     /// Restrictions on custom names, or None if you cannot use custom names.
     pub custom_names: Option<NameFeatures>,
     /// The maximum value allowed for the Expires-After header, if restricted.
     pub max_expiry_time: Option<u64>,
-    /// The MIME types allowed for file uploads.
-    pub mime_types_whitelist: Vec<String>,
-    /// MIME types disallowed for file uploads. Ignored if the whitelist is not empty.
-    pub mime_types_blacklist: Vec<String>,
+    /// Patterns for the MIME types allowed for file uploads.
+    pub mime_types_whitelist: Vec<MimePattern>,
+    /// Patterns for MIME types disallowed for file uploads. Ignored if the
+    /// whitelist is not empty.
+    pub mime_types_blacklist: Vec<MimePattern>,
+    /// The maximum number of files in a bundle.
+    pub max_bundle_members: u16,
     /// URL schemes allowed for links.
     pub link_schemes: Vec<String>,
     /// Highlighting languages allowed for pastes.
     pub highlighting_languages: Vec<String>,
+    /// Themes which can be used when rendering pastes as HTML.
+    pub highlighting_themes: Vec<String>,
 }
 
 impl Abilities {
-    pub fn load(config: &Config, auth: &Auth) -> Result<Abilities, status::Custom<String>> {
+    pub fn load(
+        config: &Config,
+        renderer: &Renderer,
+        auth: &Auth,
+    ) -> Result<Abilities, status::Custom<String>> {
         let login = !config.passwords.is_empty();
         let permissions = auth.get_permissions()?;
         let create_any = permissions.contains(&Permission::CreateAny);
         let create_file = create_any || permissions.contains(&Permission::CreateFile);
         let create_link = create_any || permissions.contains(&Permission::CreateLink);
         let create_paste = create_any || permissions.contains(&Permission::CreatePaste);
+        let create_bundle = create_any || permissions.contains(&Permission::CreateBundle);
         let update_any = permissions.contains(&Permission::UpdateAny);
         let update_own = update_any || permissions.contains(&Permission::UpdateOwn);
+        let read_private = update_any || permissions.contains(&Permission::ReadPrivate);
         let custom_names = if permissions.contains(&Permission::CustomName) {
             Some(NameFeatures {
                 min_length: config.names.min_length,
@@ -60,21 +77,27 @@ impl Abilities {
         let max_expiry_time = config.restrictions.max_expiry_time.map(|x| x.as_secs());
         let mime_types_whitelist = config.restrictions.allowed_mime_types.clone();
         let mime_types_blacklist = config.restrictions.disallowed_mime_types.clone();
+        let max_bundle_members = config.restrictions.max_bundle_members;
         let link_schemes = config.restrictions.allowed_link_schemes.clone();
         let highlighting_languages = config.highlighting_languages.clone();
+        let highlighting_themes = renderer.theme_names();
         Ok(Abilities {
             login,
             create_file,
             create_paste,
             create_link,
+            create_bundle,
             update_own,
             update_any,
+            read_private,
             custom_names,
             max_expiry_time,
             mime_types_whitelist,
             mime_types_blacklist,
+            max_bundle_members,
             link_schemes,
             highlighting_languages,
+            highlighting_themes,
         })
     }
 }
@@ -0,0 +1,217 @@
+//! Tools for counting clicks on link shares.
+//!
+//! Clicks are only stored as counts, and the addresses of clients are only
+//! used to look up their country, so no individual click can be traced back.
+use crate::config::Config;
+use crate::headers::HeaderParams;
+use crate::models::LinkClick;
+use crate::schema::link_clicks;
+use crate::DbConn;
+use chrono::{NaiveDate, Utc};
+use diesel::{insert_into, ExpressionMethods, QueryDsl, RunQueryDsl};
+use maxminddb::geoip2;
+use rocket::http::Status;
+use rocket::response::status;
+use serde::Serialize;
+use std::collections::HashMap;
+use url::Url;
+
+/// Browser families, matched in order against the User-Agent header, since
+/// most browsers claim to be several others too.
+const USER_AGENT_FAMILIES: &[(&str, &str)] = &[
+    ("bot", "Bot"),
+    ("crawler", "Bot"),
+    ("spider", "Bot"),
+    ("Edg/", "Edge"),
+    ("OPR/", "Opera"),
+    ("SamsungBrowser/", "Samsung Internet"),
+    ("Firefox/", "Firefox"),
+    ("FxiOS/", "Firefox"),
+    ("Chrome/", "Chrome"),
+    ("CriOS/", "Chrome"),
+    ("Safari/", "Safari"),
+    ("curl/", "curl"),
+    ("Wget/", "Wget"),
+];
+
+fn database_error() -> status::Custom<String> {
+    status::Custom(Status::InternalServerError, "Database error.".into())
+}
+
+/// The longest value which can be stored for the referrer host.
+const MAX_REFERRER_HOST_LENGTH: usize = 255;
+
+/// The host of the page a link was followed from.
+///
+/// Hosts too long to store are dropped, since a shortened one would be wrong.
+fn referrer_host(referrer: Option<&str>) -> Option<String> {
+    let url = Url::parse(referrer?).ok()?;
+    url.host_str()
+        .filter(|host| host.len() <= MAX_REFERRER_HOST_LENGTH)
+        .map(|host| host.to_ascii_lowercase())
+}
+
+/// The family of browser a User-Agent header is from, such as "Firefox".
+fn user_agent_family(user_agent: Option<&str>) -> Option<&'static str> {
+    let user_agent = user_agent?;
+    let lower = user_agent.to_ascii_lowercase();
+    let family = USER_AGENT_FAMILIES
+        .iter()
+        .find(|(pattern, family)| match *family {
+            // Bots don't agree on capitalisation.
+            "Bot" => lower.contains(pattern),
+            _ => user_agent.contains(pattern),
+        })
+        .map_or("Other", |(_, family)| *family);
+    Some(family)
+}
+
+/// The country of the client, as an ISO code, if a GeoIP database is loaded.
+fn country(headers: &HeaderParams, conf: &Config) -> Option<String> {
+    let reader = conf.analytics.geoip.as_ref()?;
+    let found: geoip2::Country = reader.lookup(headers.client_ip?).ok()?;
+    found.country?.iso_code.map(|code| code.to_string())
+}
+
+/// Count a click on a link, if clicks are counted.
+pub fn record_click(
+    name: &str,
+    headers: &HeaderParams,
+    conf: &Config,
+    conn: &DbConn,
+) -> Result<(), status::Custom<String>> {
+    if !conf.analytics.count_clicks {
+        return Ok(());
+    }
+    let mut click = LinkClick {
+        share_name: name.to_string(),
+        day: Utc::now().date_naive(),
+        referrer_host: String::new(),
+        user_agent: String::new(),
+        country: String::new(),
+        clicks: 1,
+    };
+    if conf.analytics.details {
+        click.referrer_host = referrer_host(headers.referrer.as_deref()).unwrap_or_default();
+        click.user_agent = user_agent_family(headers.user_agent.as_deref())
+            .unwrap_or_default()
+            .to_string();
+        click.country = country(headers, conf).unwrap_or_default();
+    }
+    insert_into(link_clicks::table)
+        .values(&click)
+        .on_conflict((
+            link_clicks::share_name,
+            link_clicks::day,
+            link_clicks::referrer_host,
+            link_clicks::user_agent,
+            link_clicks::country,
+        ))
+        .do_update()
+        .set(link_clicks::clicks.eq(link_clicks::clicks + 1))
+        .execute(&conn.0)
+        .map_err(|_| database_error())?;
+    Ok(())
+}
+
+/// The number of clicks with a certain value, or an unknown one.
+#[derive(Serialize)]
+pub struct ClickCount {
+    pub value: Option<String>,
+    pub clicks: i64,
+}
+
+/// Click counts for a link, as given by the API.
+#[derive(Serialize)]
+pub struct LinkStats {
+    pub total: i64,
+    /// Clicks on each day, oldest first.
+    pub days: Vec<ClickCount>,
+    /// The rest are sorted by the most clicks first.
+    pub referrers: Vec<ClickCount>,
+    pub user_agents: Vec<ClickCount>,
+    pub countries: Vec<ClickCount>,
+}
+
+/// Total the clicks for each value, most clicks first.
+fn sum_clicks<'a>(clicks: impl Iterator<Item = (&'a str, i64)>) -> Vec<ClickCount> {
+    let mut totals: HashMap<&str, i64> = HashMap::new();
+    for (value, count) in clicks {
+        *totals.entry(value).or_default() += count;
+    }
+    let mut counts: Vec<ClickCount> = totals
+        .into_iter()
+        .map(|(value, clicks)| ClickCount {
+            value: Some(value.to_string()).filter(|value| !value.is_empty()),
+            clicks,
+        })
+        .collect();
+    counts.sort_by(|a, b| b.clicks.cmp(&a.clicks).then_with(|| a.value.cmp(&b.value)));
+    counts
+}
+
+/// Get the click counts for a link.
+pub fn get_stats(name: &str, conn: &DbConn) -> Result<LinkStats, status::Custom<String>> {
+    let clicks = link_clicks::table
+        .filter(link_clicks::share_name.eq(name))
+        .load::<LinkClick>(&conn.0)
+        .map_err(|_| database_error())?;
+    let mut days: HashMap<NaiveDate, i64> = HashMap::new();
+    for click in &clicks {
+        *days.entry(click.day).or_default() += click.clicks;
+    }
+    let mut days: Vec<(NaiveDate, i64)> = days.into_iter().collect();
+    days.sort();
+    Ok(LinkStats {
+        total: clicks.iter().map(|click| click.clicks).sum(),
+        days: days
+            .into_iter()
+            .map(|(day, clicks)| ClickCount {
+                value: Some(day.to_string()),
+                clicks,
+            })
+            .collect(),
+        referrers: sum_clicks(clicks.iter().map(|c| (c.referrer_host.as_str(), c.clicks))),
+        user_agents: sum_clicks(clicks.iter().map(|c| (c.user_agent.as_str(), c.clicks))),
+        countries: sum_clicks(clicks.iter().map(|c| (c.country.as_str(), c.clicks))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_referrer_hosts() {
+        assert_eq!(
+            referrer_host(Some("https://Example.COM/page?q=1")).as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(referrer_host(Some("not a url")), None);
+        assert_eq!(referrer_host(None), None);
+    }
+
+    #[test]
+    fn drops_long_referrer_hosts() {
+        let label = "a".repeat(63);
+        let long = format!("https://{}.{}.{}.{}.com/", label, label, label, label);
+        assert_eq!(referrer_host(Some(&long)), None);
+        let short = format!("https://{}.{}.{}.com/", label, label, label);
+        assert!(referrer_host(Some(&short)).unwrap().len() <= MAX_REFERRER_HOST_LENGTH);
+    }
+
+    #[test]
+    fn finds_user_agent_families() {
+        let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0";
+        let chrome = "Mozilla/5.0 (Windows NT 10.0) AppleWebKit/537.36 Chrome/130.0 Safari/537.36";
+        let edge = "Mozilla/5.0 AppleWebKit/537.36 Chrome/130.0 Safari/537.36 Edg/130.0";
+        let bot = "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+        assert_eq!(user_agent_family(Some(firefox)), Some("Firefox"));
+        assert_eq!(user_agent_family(Some(chrome)), Some("Chrome"));
+        assert_eq!(user_agent_family(Some(edge)), Some("Edge"));
+        assert_eq!(user_agent_family(Some(bot)), Some("Bot"));
+        assert_eq!(user_agent_family(Some("curl/8.0.1")), Some("curl"));
+        assert_eq!(user_agent_family(Some("something")), Some("Other"));
+        assert_eq!(user_agent_family(None), None);
+    }
+}
@@ -1,17 +1,36 @@
 //! API route handlers.
 use crate::abilities::Abilities;
+use crate::analytics::{get_stats, record_click, LinkStats};
+use crate::auth::check_access;
 use crate::body::Body;
+use crate::bundle::{
+    delete_members, get_member, list_members, prepare_members, save_members, ArchiveFormat,
+    MemberPath,
+};
 use crate::config::Config;
+use crate::content::content_redirect;
+use crate::detect::{read_sample, AUTO_LANGUAGE};
+use crate::diff::{compare, DiffQuery};
 use crate::headers::HeaderParams;
-use crate::models::{Share, ShareKind};
+use crate::history::{list_revisions, RevisionInfo, ShareRef};
+use crate::metadata::strip_metadata;
+use crate::models::{Revision, Share, ShareKind};
 use crate::names::{get_name, get_token};
-use crate::responses::{ShareBodyResponder, ShareCreationResponder};
+use crate::render::Renderer;
+use crate::responses::{BlobResponder, ShareBodyResponder, ShareCreationResponder};
+use crate::scanning::scan_blob;
 use crate::schema::shares;
+use crate::sniff::read_sniff_sample;
+use crate::storage::{open_blob, release_blob, store_blob};
+use crate::thumbnails::{get_thumbnail, is_image, ThumbnailQueue};
+use crate::view::{ViewOptions, ViewQuery};
 use crate::DbConn;
 use diesel::dsl::*;
 use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
-use rocket::http::Status;
-use rocket::response::status;
+use rocket::http::uri::Origin;
+use rocket::http::{ContentType, Status};
+use rocket::request::LenientForm;
+use rocket::response::{content, status, Response};
 use rocket::State;
 use rocket_contrib::json::Json;
 use std::path::PathBuf;
@@ -21,18 +40,31 @@ use std::path::PathBuf;
 pub fn create(
     conn: DbConn,
     conf: State<Config>,
+    thumbnails: State<ThumbnailQueue>,
     data: Body,
     name: Option<String>,
-    headers: HeaderParams,
+    mut headers: HeaderParams,
 ) -> Result<ShareCreationResponder, status::Custom<String>> {
+    data.apply_fields(&mut headers)?;
     let auth = headers.get_auth(&conf)?;
     let kind = headers.get_kind()?;
-    let name = get_name(&conf, &conn, &auth, name)?;
+    headers.check_encryption(kind)?;
+    let name = get_name(&conf, &conn, &auth, name.or_else(|| headers.name.clone()))?;
     let token = match auth.give_token() {
         true => Some(get_token()),
         false => None,
     };
     let mut share = Share::new(name.clone(), headers.get_expires(&conf), token, kind);
+    share.password = headers.get_password_hash()?.flatten();
+    if let Some(visibility) = headers.visibility {
+        share.visibility = visibility;
+    }
+    if kind == ShareKind::Link {
+        share.link_preview = headers.link_preview.flatten();
+        share.redirect_code = headers.redirect_code.flatten();
+        share.check_redirect_code()?;
+    }
+    let mut members = None;
     match kind {
         ShareKind::Link => {
             auth.create_link()?;
@@ -40,19 +72,58 @@ pub fn create(
         }
         ShareKind::Paste => {
             auth.create_paste()?;
-            share.language = Some(headers.get_langauage(&conf)?);
-            data.write_unicode_file(&name, &conf, &headers)?;
+            let language = headers.get_langauage(&conf)?;
+            // Encrypted contents can't be checked, or used to detect the language.
+            let (blob, sample) = match headers.encryption {
+                Some(_) => (data.write_raw_file(&conf, &headers)?.0, String::new()),
+                None => data.write_unicode_file(&conf, &headers)?,
+            };
+            scan_blob(&blob, &conf)?;
+            share.encryption = headers.encryption.clone();
+            share.set_language(language, &sample, headers.filename.as_deref(), &conf);
+            share.blob = Some(store_blob(blob, kind, &conf, &conn.0)?);
         }
         ShareKind::File => {
             auth.create_file()?;
-            share.mime_type = Some(headers.get_mime_type(&conf)?);
-            data.write_raw_file(&name, &conf, &headers)?;
+            let (mut blob, sample) = data.write_raw_file(&conf, &headers)?;
+            let mime_type = headers.get_mime_type(&sample, &conf)?;
+            let mime_type = mime_type.unwrap_or_else(|| conf.default_mime_type.clone());
+            if headers.strip_metadata(&conf) {
+                blob = strip_metadata(blob, &conf)?;
+            }
+            scan_blob(&blob, &conf)?;
+            share.mime_type = Some(mime_type);
+            share.blob = Some(store_blob(blob, kind, &conf, &conn.0)?);
+        }
+        ShareKind::Bundle => {
+            auth.create_bundle()?;
+            members = Some(prepare_members(
+                &name,
+                data.into_members(&conf, &headers)?,
+                &conf,
+            )?);
         }
     }
-    insert_into(shares::table)
+    if insert_into(shares::table)
         .values(&share)
         .execute(&conn.0)
-        .map_err(|_| status::Custom(Status::InternalServerError, "Database error.".into()))?;
+        .is_err()
+    {
+        // The contents were stored for this share, so they are released again.
+        if let Some(hash) = &share.blob {
+            release_blob(hash, &conf.upload_dir, &conn.0)?;
+        }
+        return Err(status::Custom(
+            Status::InternalServerError,
+            "Database error.".into(),
+        ));
+    }
+    if let Some(members) = members {
+        save_members(members, &conf, &conn)?;
+    }
+    if let (Some(hash), Some(mime_type)) = (&share.blob, &share.mime_type) {
+        thumbnails.push(hash, mime_type, &conf);
+    }
     Ok(share.creation_response(conf))
 }
 
@@ -60,21 +131,170 @@ pub fn create(
 pub fn create_without_name(
     conn: DbConn,
     conf: State<Config>,
+    thumbnails: State<ThumbnailQueue>,
     data: Body,
     headers: HeaderParams,
 ) -> Result<ShareCreationResponder, status::Custom<String>> {
-    create(conn, conf, data, None, headers)
+    create(conn, conf, thumbnails, data, None, headers)
+}
+
+/// Get a share by name, or a previous version of it by "name@revision".
+///
+/// Bundles can also be downloaded as an archive by adding an extension.
+#[get("/<share_ref>?<query..>")]
+pub fn get<'a>(
+    conn: DbConn,
+    conf: State<'a, Config>,
+    renderer: State<'a, Renderer>,
+    share_ref: ShareRef,
+    query: LenientForm<ViewQuery>,
+    uri: &Origin,
+    headers: HeaderParams,
+) -> Result<ShareBodyResponder<'a>, status::Custom<String>> {
+    let mut view = ViewOptions::new(&query, &headers, &conf, &renderer)?;
+    view.preview |= share_ref.preview;
+    let (share, archive) = match Share::get(share_ref.name.clone(), &conn, &conf.upload_dir) {
+        Err(e) if e.0 == Status::NotFound => match ArchiveFormat::split_name(&share_ref.name) {
+            Some((name, format)) => (
+                Share::get(name.into(), &conn, &conf.upload_dir)?,
+                Some(format),
+            ),
+            None => return Err(e),
+        },
+        share => (share?, None),
+    };
+    if archive.is_some() && share.kind != ShareKind::Bundle {
+        return Err(status::Custom(Status::NotFound, "Share not found.".into()));
+    }
+    headers.check_read(&share, &conf)?;
+    let password = query
+        .password
+        .as_deref()
+        .or(headers.access_password.as_deref());
+    if let Err(e) = check_access(&share, password) {
+        // Browsers are given a page asking for the password instead.
+        if !view.render_html {
+            return Err(e);
+        }
+        let mut response = share.body_response(conf, renderer, view);
+        response.lock(e.1);
+        return Ok(response);
+    }
+    let mut response = share.body_response(conf, renderer, view);
+    if let Some(number) = share_ref.revision {
+        response.show_revision(Revision::get(&response.name, number, &conn)?);
+    }
+    if response.kind == ShareKind::Bundle {
+        response.show_bundle(list_members(&response.name, &conn)?, archive);
+    }
+    if response.serves_contents() {
+        if let Some(url) = content_redirect(uri, &headers, &response.conf) {
+            response.redirect_to_content(url);
+        }
+    }
+    if response.redirects() {
+        // Failing to count a click shouldn't stop the link from working.
+        if let Err(e) = record_click(&response.name, &headers, &response.conf, &conn) {
+            eprintln!("Could not count click on {}: {}", response.name, e.1);
+        }
+    }
+    Ok(response)
+}
+
+/// Get a file from a bundle.
+///
+/// rank = 2 so that other routes under a share take priority.
+#[get("/<name>/<path..>?<password>", rank = 2)]
+pub fn member<'a>(
+    conn: DbConn,
+    conf: State<'a, Config>,
+    name: String,
+    path: MemberPath,
+    password: Option<String>,
+    uri: &Origin,
+    headers: HeaderParams,
+) -> Result<BlobResponder<'a>, status::Custom<String>> {
+    let share = Share::get(name, &conn, &conf.upload_dir)?;
+    headers.check_read(&share, &conf)?;
+    check_access(
+        &share,
+        password.as_deref().or(headers.access_password.as_deref()),
+    )?;
+    if share.kind != ShareKind::Bundle {
+        return Err(status::Custom(
+            Status::NotFound,
+            "Only bundles contain files.".into(),
+        ));
+    }
+    let member = get_member(&share.name, &path.0, &conn)?;
+    let content_url = content_redirect(uri, &headers, &conf);
+    Ok(BlobResponder {
+        conf,
+        blob: member.blob,
+        mime_type: member.mime_type,
+        accept_encodings: headers.accept_encodings,
+        content_url,
+    })
+}
+
+/// Get a thumbnail of an image file share.
+#[get("/<name>/thumb?<size>&<password>")]
+pub fn thumbnail(
+    conn: DbConn,
+    conf: State<Config>,
+    thumbnails: State<ThumbnailQueue>,
+    name: String,
+    size: Option<u32>,
+    password: Option<String>,
+    headers: HeaderParams,
+) -> Result<content::Content<Vec<u8>>, status::Custom<String>> {
+    let share = Share::get(name, &conn, &conf.upload_dir)?;
+    headers.check_read(&share, &conf)?;
+    check_access(
+        &share,
+        password.as_deref().or(headers.access_password.as_deref()),
+    )?;
+    let sizes = &conf.thumbnails.sizes;
+    let size = match size.or_else(|| sizes.first().copied()) {
+        Some(size) if sizes.contains(&size) => size,
+        _ => {
+            return Err(status::Custom(
+                Status::BadRequest,
+                "Thumbnails are not available at this size.".into(),
+            ))
+        }
+    };
+    let hash = match (share.kind, &share.blob, &share.mime_type) {
+        (ShareKind::File, Some(hash), Some(mime_type)) if is_image(mime_type) => hash,
+        _ => {
+            return Err(status::Custom(
+                Status::NotFound,
+                "Only images have thumbnails.".into(),
+            ))
+        }
+    };
+    Ok(content::Content(
+        ContentType::PNG,
+        get_thumbnail(hash, size, &thumbnails, &conf)?,
+    ))
 }
 
-/// Get a share by name.
-#[get("/<name>")]
-pub fn get(
+/// List the previous versions of a share.
+#[get("/<name>/revisions?<password>")]
+pub fn revisions(
     conn: DbConn,
     conf: State<Config>,
     name: String,
+    password: Option<String>,
     headers: HeaderParams,
-) -> Result<ShareBodyResponder, status::Custom<String>> {
-    Ok(Share::get(name, &conn, &conf.upload_dir)?.body_response(conf, headers.accept_redirect))
+) -> Result<Json<Vec<RevisionInfo>>, status::Custom<String>> {
+    let share = Share::get(name, &conn, &conf.upload_dir)?;
+    headers.check_read(&share, &conf)?;
+    check_access(
+        &share,
+        password.as_deref().or(headers.access_password.as_deref()),
+    )?;
+    Ok(Json(list_revisions(&share.name, &conn)?))
 }
 
 /// Delete a share.
@@ -87,61 +307,196 @@ pub fn delete(
 ) -> Result<status::NoContent, status::Custom<String>> {
     let share = Share::get(name, &conn, &conf.upload_dir)?;
     headers.get_auth(&conf)?.update_share(&share)?;
-    if share.kind != ShareKind::Link {
-        share.delete_file(&conf.upload_dir)?;
+    // Another request may have deleted the share since it was found.
+    if !Share::delete(&share.name, false, &conn.0, &conf.upload_dir)? {
+        return Err(status::Custom(Status::NotFound, "Share not found.".into()));
     }
-    diesel::delete(shares::table.filter(shares::name.eq(share.name)))
-        .execute(&conn.0)
-        .map_err(|_| status::Custom(Status::InternalServerError, "Database error.".into()))?;
     Ok(status::NoContent)
 }
 
 /// Edit a share.
 #[patch("/<name>", data = "<data>")]
-pub fn update(
+pub fn update<'a>(
     conn: DbConn,
-    conf: State<Config>,
+    conf: State<'a, Config>,
+    renderer: State<'a, Renderer>,
+    thumbnails: State<ThumbnailQueue>,
     data: Body,
     name: String,
-    headers: HeaderParams,
-) -> Result<ShareBodyResponder, status::Custom<String>> {
+    mut headers: HeaderParams,
+) -> Result<ShareBodyResponder<'a>, status::Custom<String>> {
+    data.apply_fields(&mut headers)?;
+    let view = ViewOptions::new(&ViewQuery::default(), &headers, &conf, &renderer)?;
     let mut share = Share::get(name, &conn, &conf.upload_dir)?;
     headers.get_auth(&conf)?.update_share(&share)?;
+    headers.check_encryption(share.kind)?;
     share.expiry = headers.get_expires(&conf);
-    if headers.content_length.unwrap_or(0) > 0 {
+    if let Some(visibility) = headers.visibility {
+        share.visibility = visibility;
+    }
+    if share.kind == ShareKind::Link {
+        if let Some(link_preview) = headers.link_preview {
+            share.link_preview = link_preview;
+        }
+        if let Some(redirect_code) = headers.redirect_code {
+            share.redirect_code = redirect_code;
+        }
+        share.check_redirect_code()?;
+    }
+    if let Some(password) = headers.get_password_hash()? {
+        // The changeset below skips None, so removing the password is done here.
+        diesel::update(shares::table.find(&share.name))
+            .set(shares::password.eq(&password))
+            .execute(&conn.0)
+            .map_err(|_| status::Custom(Status::InternalServerError, "Database error.".into()))?;
+        share.password = password;
+    }
+    let mut sample = None;
+    let has_content = data.has_content(&headers);
+    if has_content {
         match share.kind {
             ShareKind::Link => {
                 share.link = Some(data.get_link(&conf, &headers)?);
             }
             ShareKind::Paste => {
-                data.write_unicode_file(&share.name, &conf, &headers)?;
+                let (blob, new_sample) = match headers.encryption {
+                    Some(_) => (data.write_raw_file(&conf, &headers)?.0, String::new()),
+                    None => data.write_unicode_file(&conf, &headers)?,
+                };
+                scan_blob(&blob, &conf)?;
+                share.replace_blob(blob, &conf, &conn)?;
+                share.encryption = headers.encryption.clone();
+                sample = Some(new_sample);
             }
             ShareKind::File => {
-                data.write_raw_file(&share.name, &conf, &headers)?;
+                let (mut blob, file_sample) = data.write_raw_file(&conf, &headers)?;
+                // This is checked before anything is replaced, but the old
+                // type is kept in the revision.
+                let mime_type = headers.get_mime_type(&file_sample, &conf)?;
+                if headers.strip_metadata(&conf) {
+                    blob = strip_metadata(blob, &conf)?;
+                }
+                scan_blob(&blob, &conf)?;
+                share.replace_blob(blob, &conf, &conn)?;
+                share.mime_type = mime_type.or_else(|| share.mime_type.take());
+            }
+            ShareKind::Bundle => {
+                let members =
+                    prepare_members(&share.name, data.into_members(&conf, &headers)?, &conf)?;
+                delete_members(&share.name, &conf, &conn)?;
+                save_members(members, &conf, &conn)?;
             }
         }
     }
-    if headers.language.is_some() && share.kind == ShareKind::Paste {
-        share.language = Some(headers.get_langauage(&conf)?);
+    if share.kind == ShareKind::Paste {
+        // A detected language is detected again if the contents change.
+        let language = match headers.language {
+            Some(_) => Some(headers.get_langauage(&conf)?),
+            None if share.language_detected && sample.is_some() => Some(AUTO_LANGUAGE.into()),
+            None => None,
+        };
+        if let Some(language) = language {
+            let sample = match sample {
+                Some(sample) => sample,
+                None if language == AUTO_LANGUAGE && share.encryption.is_none() => {
+                    match &share.blob {
+                        Some(hash) => read_sample(open_blob(hash, &conf).map_err(|_| {
+                            status::Custom(
+                                Status::InternalServerError,
+                                "Could not open file.".into(),
+                            )
+                        })?)?,
+                        None => String::new(),
+                    }
+                }
+                None => String::new(),
+            };
+            share.set_language(language, &sample, headers.filename.as_deref(), &conf);
+        }
     }
-    if headers.mime_type.is_some() && share.kind == ShareKind::File {
-        share.mime_type = Some(headers.get_mime_type(&conf)?);
+    if headers.mime_type.is_some() && share.kind == ShareKind::File && !has_content {
+        let file_sample = match &share.blob {
+            Some(hash) => read_sniff_sample(open_blob(hash, &conf).map_err(|_| {
+                status::Custom(Status::InternalServerError, "Could not open file.".into())
+            })?)?,
+            None => vec![],
+        };
+        share.mime_type = headers.get_mime_type(&file_sample, &conf)?;
     }
     diesel::update(shares::table.filter(shares::name.eq(share.name.clone())))
         .set(&share)
         .execute(&conn.0)
         .map_err(|_| status::Custom(Status::InternalServerError, "Database error.".into()))?;
-    Ok(share.body_response(conf, headers.accept_redirect))
+    if share.kind == ShareKind::Paste {
+        // The changeset above skips None, so plain contents, and encrypted
+        // ones which lost their detected language, are marked here.
+        diesel::update(shares::table.find(&share.name))
+            .set((
+                shares::encryption.eq(&share.encryption),
+                shares::language.eq(&share.language),
+            ))
+            .execute(&conn.0)
+            .map_err(|_| status::Custom(Status::InternalServerError, "Database error.".into()))?;
+    }
+    if share.kind == ShareKind::Link {
+        // The changeset above skips None, so links reset to the server's
+        // defaults are marked here.
+        diesel::update(shares::table.find(&share.name))
+            .set((
+                shares::link_preview.eq(&share.link_preview),
+                shares::redirect_code.eq(&share.redirect_code),
+            ))
+            .execute(&conn.0)
+            .map_err(|_| status::Custom(Status::InternalServerError, "Database error.".into()))?;
+    }
+    if has_content || headers.mime_type.is_some() {
+        if let (Some(hash), Some(mime_type)) = (&share.blob, &share.mime_type) {
+            thumbnails.push(hash, mime_type, &conf);
+        }
+    }
+    Ok(share.body_response(conf, renderer, view))
 }
 
 /// Get information on the features this server supports.
 #[get("/meta/abilities")]
 pub fn abilities(
     conf: State<Config>,
+    renderer: State<Renderer>,
     headers: HeaderParams,
 ) -> Result<Json<Abilities>, status::Custom<String>> {
     let auth = headers.get_auth(&conf)?;
-    Ok(Json(Abilities::load(&conf, &auth)?))
+    Ok(Json(Abilities::load(&conf, &renderer, &auth)?))
+}
+
+/// Get the click counts for a link.
+#[get("/meta/shares/<name>/stats")]
+pub fn stats(
+    conn: DbConn,
+    conf: State<Config>,
+    name: String,
+    headers: HeaderParams,
+) -> Result<Json<LinkStats>, status::Custom<String>> {
+    let share = Share::get(name, &conn, &conf.upload_dir)?;
+    headers.get_auth(&conf)?.update_share(&share)?;
+    if share.kind != ShareKind::Link {
+        return Err(status::Custom(
+            Status::BadRequest,
+            "Only links have click counts.".into(),
+        ));
+    }
+    Ok(Json(get_stats(&share.name, &conn)?))
+}
+
+/// Compare two pastes, or revisions of pastes.
+#[get("/meta/diff?<query..>")]
+pub fn diff(
+    conn: DbConn,
+    conf: State<Config>,
+    renderer: State<Renderer>,
+    query: LenientForm<DiffQuery>,
+    headers: HeaderParams,
+) -> Result<Response<'static>, status::Custom<String>> {
+    compare(&query, &headers, &conf, &renderer, &conn)
 }
 
 /// Catch-all to return a 404 error.
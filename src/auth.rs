@@ -1,9 +1,15 @@
 //! Tools for checking client authentication and authorisation.
 use crate::config::{Config, Permission, DEFAULT_PASSWORD};
-use crate::models::Share;
+use crate::models::{Share, Visibility};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
 use rocket::http::Status;
 use rocket::response::status;
 
+/// The Authorization header method used to give the password of a share.
+pub const ACCESS_METHOD: &str = "access";
+
 pub enum Auth<'a> {
     Password(&'a [Permission]),
     Default(&'a [Permission]),
@@ -28,6 +34,10 @@ impl<'a> Auth<'a> {
                 })
                 .map(|p| Auth::Password(p)),
             "token" => Ok(Auth::Token(content.to_string())),
+            ACCESS_METHOD => Err(status::Custom(
+                Status::BadRequest,
+                "Access passwords are only needed to get shares.".into(),
+            )),
             _ => Err(status::Custom(
                 Status::BadRequest,
                 "Authorization header method must be 'Password' or 'Token'.".into(),
@@ -110,10 +120,31 @@ impl<'a> Auth<'a> {
         self.can_create_share(Permission::CreatePaste, "create a paste")
     }
 
+    pub fn create_bundle(&self) -> Result<(), status::Custom<String>> {
+        self.can_create_share(Permission::CreateBundle, "create a bundle")
+    }
+
     pub fn custom_name(&self) -> Result<(), status::Custom<String>> {
         self.has_permission(Permission::CustomName, "use a custom name")
     }
 
+    /// Ensure that the client may create shares of some kind, or update any
+    /// share, so that it is worth reading what it uploads.
+    pub fn may_upload(&self) -> Result<(), status::Custom<String>> {
+        let allowed = self.get_permissions()?.iter().any(|permission| {
+            matches!(
+                permission,
+                Permission::CreateAny
+                    | Permission::CreateLink
+                    | Permission::CreateFile
+                    | Permission::CreatePaste
+                    | Permission::CreateBundle
+                    | Permission::UpdateAny
+            )
+        });
+        self.assert_true(allowed, "create or update shares")
+    }
+
     pub fn give_token(&self) -> bool {
         let permissions = match self {
             Auth::Token(_) => return false,
@@ -123,6 +154,25 @@ impl<'a> Auth<'a> {
         permissions.contains(&Permission::UpdateOwn) || permissions.contains(&Permission::UpdateAny)
     }
 
+    /// Ensure that the client may read a share.
+    ///
+    /// Private shares which can't be read are reported as not found, so that
+    /// their names aren't revealed.
+    pub fn read_share(&self, share: &Share) -> Result<(), status::Custom<String>> {
+        let allowed = match self {
+            _ if share.visibility != Visibility::Private => true,
+            Auth::Token(token) => Some(token) == share.token.as_ref(),
+            Auth::Password(permissions) | Auth::Default(permissions) => {
+                permissions.contains(&Permission::ReadPrivate)
+                    || permissions.contains(&Permission::UpdateAny)
+            }
+        };
+        match allowed {
+            true => Ok(()),
+            false => Err(status::Custom(Status::NotFound, "Share not found.".into())),
+        }
+    }
+
     pub fn update_share(&self, share: &Share) -> Result<(), status::Custom<String>> {
         match self {
             Auth::Token(token) => {
@@ -139,3 +189,37 @@ impl<'a> Auth<'a> {
         }
     }
 }
+
+/// Hash a password which will be needed to access a share.
+pub fn hash_access_password(password: &str) -> Result<String, status::Custom<String>> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|_| {
+            status::Custom(
+                Status::InternalServerError,
+                "Could not hash password.".into(),
+            )
+        })
+}
+
+/// Ensure that the client gave the password of a share, if it has one.
+pub fn check_access(share: &Share, given: Option<&str>) -> Result<(), status::Custom<String>> {
+    let hash = match &share.password {
+        Some(hash) => hash,
+        None => return Ok(()),
+    };
+    let given = given.ok_or_else(|| {
+        status::Custom(
+            Status::Unauthorized,
+            "This share is password protected.".into(),
+        )
+    })?;
+    let hash = PasswordHash::new(hash).map_err(|_| {
+        status::Custom(Status::InternalServerError, "Invalid password hash.".into())
+    })?;
+    Argon2::default()
+        .verify_password(given.as_bytes(), &hash)
+        .map_err(|_| status::Custom(Status::Unauthorized, "Incorrect share password.".into()))
+}
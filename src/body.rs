@@ -1,25 +1,97 @@
 //! Tools for reading the request body.
+use crate::auth::Auth;
+use crate::bundle::unpack_tar;
 use crate::config::Config;
+use crate::detect::SAMPLE_LENGTH;
+use crate::digest::{hash_str, DigestReader};
+use crate::form::{FormBody, FormFile};
 use crate::headers::HeaderParams;
-use rocket::data::{Data, DataStream, FromDataSimple, Outcome};
-use rocket::http::Status;
+use crate::links::check_link_host;
+use crate::models::Share;
+use crate::sniff::SNIFF_LENGTH;
+use crate::storage::{BlobWriter, NewBlob};
+use crate::DbConn;
+use rocket::data::{Data, FromDataSimple, Outcome};
+use rocket::http::{Method, Status};
 use rocket::request::Request;
 use rocket::response::status;
-use std::fs::File;
-use std::io::{copy, BufReader, BufWriter, Read, Take, Write};
+use rocket::State;
+use std::io::{copy, BufReader, Read, Write};
 use unicode_reader::CodePoints;
 use url::Url;
 
-pub struct Body(Data);
+pub enum Body {
+    /// The contents of the share, sent directly as the request body.
+    Raw(Data),
+    /// The contents and metadata of the share, sent as multipart/form-data.
+    Form(FormBody),
+}
 
 impl Body {
+    /// Fill in share metadata given as form fields.
+    ///
+    /// A Content-Digest header is checked here for forms, since it applies to
+    /// the whole form rather than the share contents.
+    pub fn apply_fields(&self, headers: &mut HeaderParams) -> Result<(), status::Custom<String>> {
+        match self {
+            Body::Raw(_) => Ok(()),
+            Body::Form(form) => {
+                headers.check_digest(&form.digest)?;
+                headers.apply_form(form)
+            }
+        }
+    }
+
+    /// Whether the share contents are the whole body, so that a Content-Digest
+    /// header applies to them.
+    fn is_raw(&self) -> bool { matches!(self, Body::Raw(_)) }
+
+    /// Whether new contents for the share were given.
+    pub fn has_content(&self, headers: &HeaderParams) -> bool {
+        match self {
+            Body::Raw(_) => headers.content_length.unwrap_or(0) > 0,
+            Body::Form(form) => form.has_content(),
+        }
+    }
+
     fn open(
         self,
         limit: u64,
         headers: &HeaderParams,
-    ) -> Result<Take<DataStream>, status::Custom<String>> {
-        headers.limit_content_length(limit)?;
-        Ok(self.0.open().take(limit))
+    ) -> Result<Box<dyn Read>, status::Custom<String>> {
+        match self {
+            Body::Raw(data) => {
+                headers.limit_content_length(limit)?;
+                Ok(Box::new(data.open().take(limit)))
+            }
+            Body::Form(form) => form.into_content()?.open(limit),
+        }
+    }
+
+    /// Take the files uploaded to create a bundle, unpacking them if needed.
+    pub fn into_members(
+        self,
+        conf: &Config,
+        headers: &HeaderParams,
+    ) -> Result<Vec<FormFile>, status::Custom<String>> {
+        match self {
+            Body::Raw(data) if headers.is_tar() => {
+                let limit = conf.restrictions.max_upload_size.get_bytes();
+                headers.limit_content_length(limit)?;
+                let mut stream = DigestReader::new(data.open().take(limit));
+                let files = unpack_tar(&mut stream, conf)?;
+                let digest = stream.finish().map_err(|_| {
+                    status::Custom(Status::BadRequest, "Could not read tar archive.".into())
+                })?;
+                headers.check_digest(&digest)?;
+                Ok(files)
+            }
+            Body::Raw(_) => Err(status::Custom(
+                Status::BadRequest,
+                "Bundles must be uploaded as a multipart form or a tar archive.".into(),
+            )),
+            Body::Form(form) => form.into_files(),
+        }
     }
 
     pub fn get_link(
@@ -27,11 +99,15 @@ impl Body {
         conf: &Config,
         headers: &HeaderParams,
     ) -> Result<String, status::Custom<String>> {
+        let is_raw = self.is_raw();
         let mut stream = self.open(conf.restrictions.max_link_length.into(), headers)?;
         let mut raw = String::new();
         stream.read_to_string(&mut raw).map_err(|_| {
             status::Custom(Status::BadRequest, "Could not read or decode body.".into())
         })?;
+        if is_raw {
+            headers.check_digest(&hash_str(&raw))?;
+        }
         let url = Url::parse(&raw)
             .map_err(|_| status::Custom(Status::BadRequest, "Invalid URL.".into()))?;
         let link_schemes = &conf.restrictions.allowed_link_schemes;
@@ -41,6 +117,7 @@ impl Body {
                 "Invalid URL scheme.".into(),
             ));
         }
+        check_link_host(&url, &conf.restrictions)?;
         Ok(url.as_str().to_string())
     }
 
@@ -48,50 +125,76 @@ impl Body {
         self,
         conf: &Config,
         headers: &HeaderParams,
-    ) -> Result<Take<DataStream>, status::Custom<String>> {
+    ) -> Result<Box<dyn Read>, status::Custom<String>> {
         self.open(conf.restrictions.max_upload_size.get_bytes(), headers)
     }
 
-    fn get_out_stream(
-        &self,
-        name: &str,
-        conf: &Config,
-    ) -> Result<BufWriter<File>, status::Custom<String>> {
-        let mut path = conf.upload_dir.clone();
-        path.push(name);
-        let file = File::create(path).map_err(|_| {
-            status::Custom(Status::InternalServerError, "Could not open file.".into())
+    fn get_out_stream(conf: &Config) -> Result<BlobWriter, status::Custom<String>> {
+        BlobWriter::create(conf)
+            .map_err(|_| status::Custom(Status::InternalServerError, "Could not open file.".into()))
+    }
+
+    fn finish_out_stream(
+        out_stream: BlobWriter,
+        is_raw: bool,
+        headers: &HeaderParams,
+    ) -> Result<NewBlob, status::Custom<String>> {
+        let blob = out_stream.finish().map_err(|_| {
+            status::Custom(Status::InternalServerError, "Could not write file.".into())
         })?;
-        Ok(BufWriter::new(file))
+        if is_raw {
+            headers.check_digest(&blob.hash)?;
+        }
+        Ok(blob)
     }
 
+    /// Write a file to a new blob, which should then be stored.
+    ///
+    /// Also returns the start of the file, for sniffing its MIME type.
     pub fn write_raw_file(
         self,
-        name: &str,
         conf: &Config,
         headers: &HeaderParams,
-    ) -> Result<(), status::Custom<String>> {
-        let mut out_stream = self.get_out_stream(name, conf)?;
+    ) -> Result<(NewBlob, Vec<u8>), status::Custom<String>> {
+        let is_raw = self.is_raw();
         let mut in_stream = self.get_in_stream(conf, headers)?;
-        copy(&mut in_stream, &mut out_stream).map_err(|_| {
-            status::Custom(Status::InternalServerError, "Could not write file.".into())
-        })?;
-        Ok(())
+        let mut out_stream = Self::get_out_stream(conf)?;
+        let write_error =
+            |_| status::Custom(Status::InternalServerError, "Could not write file.".into());
+        let mut sample = Vec::with_capacity(SNIFF_LENGTH);
+        in_stream
+            .by_ref()
+            .take(SNIFF_LENGTH as u64)
+            .read_to_end(&mut sample)
+            .map_err(write_error)?;
+        out_stream.write_all(&sample).map_err(write_error)?;
+        copy(&mut in_stream, &mut out_stream).map_err(write_error)?;
+        Ok((
+            Self::finish_out_stream(out_stream, is_raw, headers)?,
+            sample,
+        ))
     }
 
+    /// Write a paste to a new blob, ensuring it is valid UTF-8.
+    ///
+    /// Also returns the start of the paste, for language detection.
     pub fn write_unicode_file(
         self,
-        name: &str,
         conf: &Config,
         headers: &HeaderParams,
-    ) -> Result<(), status::Custom<String>> {
-        let mut out_stream = self.get_out_stream(name, conf)?;
+    ) -> Result<(NewBlob, String), status::Custom<String>> {
+        let is_raw = self.is_raw();
         let code_points = CodePoints::from(BufReader::new(self.get_in_stream(conf, headers)?));
+        let mut out_stream = Self::get_out_stream(conf)?;
+        let mut sample = String::new();
         for code_point in code_points {
             match code_point {
                 Ok(c) => {
+                    if sample.len() < SAMPLE_LENGTH {
+                        sample.push(c);
+                    }
                     out_stream
-                        .write(c.encode_utf8(&mut [0; 4]).as_bytes())
+                        .write_all(c.encode_utf8(&mut [0; 4]).as_bytes())
                         .map_err(|_| {
                             status::Custom(
                                 Status::InternalServerError,
@@ -107,14 +210,75 @@ impl Body {
                 }
             }
         }
-        Ok(())
+        Ok((
+            Self::finish_out_stream(out_stream, is_raw, headers)?,
+            sample,
+        ))
+    }
+}
+
+/// Check that the client may create or update a share, before a form is
+/// written to disk.
+///
+/// This is checked again by the route, along with the kind of share.
+fn check_uploader(request: &Request, conf: &Config) -> Result<(), status::Custom<String>> {
+    let raw_auth = request
+        .headers()
+        .get_one("Authorization")
+        .map(|s| s.to_string());
+    let auth = Auth::from_header(&raw_auth, conf)?;
+    match auth {
+        // Tokens can only be used to update the share they were given for.
+        Auth::Token(_) if request.method() == Method::Patch => {
+            let name = match request.get_param::<String>(0) {
+                Some(Ok(name)) => name,
+                _ => return Err(status::Custom(Status::NotFound, "Share not found.".into())),
+            };
+            let conn = request.guard::<DbConn>().succeeded().ok_or_else(|| {
+                status::Custom(Status::InternalServerError, "Database error.".into())
+            })?;
+            let share = Share::get(name, &conn, &conf.upload_dir)?;
+            auth.update_share(&share)
+        }
+        _ => auth.may_upload(),
     }
 }
 
 impl FromDataSimple for Body {
     type Error = String;
 
-    fn from_data(_request: &Request, data: Data) -> Outcome<Self, String> {
-        Outcome::Success(Body(data))
+    fn from_data(request: &Request, data: Data) -> Outcome<Self, String> {
+        let boundary = match request.content_type() {
+            Some(content_type) if content_type.is_form_data() => content_type
+                .params()
+                .find(|(key, _)| *key == "boundary")
+                .map(|(_, value)| value.to_string()),
+            _ => return Outcome::Success(Body::Raw(data)),
+        };
+        let boundary = match boundary {
+            Some(boundary) => boundary,
+            None => {
+                return Outcome::Failure((
+                    Status::BadRequest,
+                    "Content-Type is missing a multipart boundary.".into(),
+                ))
+            }
+        };
+        let conf = match request.guard::<State<Config>>().succeeded() {
+            Some(conf) => conf,
+            None => {
+                return Outcome::Failure((
+                    Status::InternalServerError,
+                    "Configuration unexpectedly missing.".into(),
+                ))
+            }
+        };
+        if let Err(e) = check_uploader(request, &conf) {
+            return Outcome::Failure((e.0, e.1));
+        }
+        match FormBody::read(data, &boundary, &conf) {
+            Ok(form) => Outcome::Success(Body::Form(form)),
+            Err(e) => Outcome::Failure(e),
+        }
     }
 }
@@ -0,0 +1,527 @@
+//! Tools for shares made up of several files.
+use crate::config::Config;
+use crate::form::FormFile;
+use crate::mime::is_valid_mime_type;
+use crate::models::{BundleMember, ShareKind};
+use crate::render::html_page;
+use crate::scanning::scan_blob;
+use crate::schema::bundle_members;
+use crate::sniff::is_generic;
+use crate::storage::{open_blob, release_blob, store_blob, BlobWriter, NewBlob};
+use crate::temp::TempFile;
+use crate::DbConn;
+use diesel::{insert_into, ExpressionMethods, QueryDsl, RunQueryDsl};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rocket::http::uri::{Segments, Uri};
+use rocket::http::{ContentType, RawStr, Status};
+use rocket::request::FromSegments;
+use rocket::response::status;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::io::{self, copy, BufWriter, Read, Seek, Write};
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tar::Archive;
+use url::form_urlencoded;
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+/// Member paths which would be shadowed by other routes.
+const RESERVED_PATHS: &[&str] = &["revisions", "thumb"];
+
+const MAX_PATH_LENGTH: usize = 1024;
+
+/// The content type of uploads which are unpacked into the members of a bundle.
+pub const TAR_MIME_TYPE: &str = "application/x-tar";
+
+/// A format a bundle can be downloaded in, by adding its extension to the name.
+#[derive(Copy, Clone)]
+pub enum ArchiveFormat {
+    Zip,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    const ALL: [ArchiveFormat; 2] = [ArchiveFormat::Zip, ArchiveFormat::TarGz];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => ".zip",
+            ArchiveFormat::TarGz => ".tar.gz",
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::TarGz => "application/gzip",
+        }
+    }
+
+    /// Split an archive name into the bundle name and archive format.
+    pub fn split_name(name: &str) -> Option<(&str, ArchiveFormat)> {
+        Self::ALL.iter().find_map(|format| {
+            name.strip_suffix(format.extension())
+                .filter(|stem| !stem.is_empty())
+                .map(|stem| (stem, *format))
+        })
+    }
+}
+
+/// The path of a file within a bundle, taken from the rest of the URL.
+pub struct MemberPath(pub String);
+
+impl<'a> FromSegments<'a> for MemberPath {
+    type Error = String;
+
+    fn from_segments(segments: Segments<'a>) -> Result<Self, Self::Error> {
+        let decoded = segments
+            .map(|segment| {
+                RawStr::from_str(segment)
+                    .percent_decode()
+                    .map(|s| s.into_owned())
+            })
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(|_| "Path is not valid UTF-8.".to_string())?;
+        clean_member_path(&decoded.join("/"))
+            .map(MemberPath)
+            .map_err(|e| e.1)
+    }
+}
+
+/// A bundle member, as listed in the manifest.
+#[derive(Serialize)]
+pub struct MemberInfo {
+    pub path: String,
+    pub url: String,
+    pub size: i64,
+    pub mime_type: String,
+    /// The SHA-256 digest of the file, as hex.
+    pub sha256: String,
+}
+
+/// The list of files in a bundle.
+#[derive(Serialize)]
+pub struct Manifest {
+    pub name: String,
+    pub members: Vec<MemberInfo>,
+    /// Links to download the whole bundle as an archive.
+    pub archives: Vec<String>,
+}
+
+impl Manifest {
+    /// Any password given is added to the links, for browsers which were
+    /// given a page asking for it.
+    pub fn new(
+        name: &str,
+        members: Vec<BundleMember>,
+        password: Option<&str>,
+        conf: &Config,
+    ) -> Self {
+        let url = format!("{}{}", conf.network.host, name);
+        let query = match password {
+            Some(password) => format!(
+                "?password={}",
+                form_urlencoded::byte_serialize(password.as_bytes()).collect::<String>()
+            ),
+            None => String::new(),
+        };
+        let members = members
+            .into_iter()
+            .map(|member| MemberInfo {
+                url: format!("{}/{}{}", url, encode_member_path(&member.path), query),
+                path: member.path,
+                size: member.size,
+                mime_type: member.mime_type,
+                sha256: member.blob,
+            })
+            .collect();
+        let archives = ArchiveFormat::ALL
+            .iter()
+            .map(|format| format!("{}{}{}", url, format.extension(), query))
+            .collect();
+        Manifest {
+            name: name.to_string(),
+            members,
+            archives,
+        }
+    }
+
+    /// Render the manifest as an HTML index page.
+    pub fn html_page(&self) -> String {
+        let mut body = format!(
+            "<article class=\"bundle\">\n<h1>{}</h1>\n<table>\n",
+            self.name
+        );
+        for member in &self.members {
+            body.push_str(&format!(
+                "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+                member.url,
+                ammonia::clean_text(&member.path),
+                member.size,
+                ammonia::clean_text(&member.mime_type)
+            ));
+        }
+        body.push_str("</table>\n<p>Download as ");
+        let archives: Vec<String> = self
+            .archives
+            .iter()
+            .zip(ArchiveFormat::ALL.iter())
+            .map(|(url, format)| format!("<a href=\"{}\">{}</a>", url, format.extension()))
+            .collect();
+        body.push_str(&archives.join(" or "));
+        body.push_str("</p>\n</article>");
+        html_page(&self.name, &body, "#ffffff")
+    }
+}
+
+fn encode_member_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| Uri::percent_encode(segment).into_owned())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// Normalise the path of a bundle member, rejecting any which are unsafe.
+///
+/// Members are stored by position, so this is about giving them sensible
+/// names in the manifest and in archives rather than about the filesystem.
+pub fn clean_member_path(raw: &str) -> Result<String, status::Custom<String>> {
+    let invalid = |reason: &str| {
+        status::Custom(
+            Status::BadRequest,
+            format!("Invalid bundle file path {:?}: {}.", raw, reason),
+        )
+    };
+    let mut segments = vec![];
+    for segment in raw.split(|c| c == '/' || c == '\\') {
+        match segment {
+            "" | "." => {}
+            ".." => return Err(invalid("must not contain \"..\"")),
+            _ if segment.chars().any(char::is_control) => {
+                return Err(invalid("must not contain control characters"))
+            }
+            _ => segments.push(segment),
+        }
+    }
+    let path = segments.join("/");
+    if path.is_empty() {
+        return Err(invalid("must not be empty"));
+    }
+    if path.len() > MAX_PATH_LENGTH {
+        return Err(invalid("too long"));
+    }
+    if RESERVED_PATHS.contains(&path.as_str()) {
+        return Err(invalid("reserved"));
+    }
+    Ok(path)
+}
+
+/// Work out the MIME type of a member from the type given by the client, or
+/// failing that its extension.
+fn member_mime_type(file: &FormFile, path: &str, conf: &Config) -> String {
+    file.mime_type
+        .clone()
+        .filter(|mime_type| !is_generic(mime_type))
+        .or_else(|| {
+            let extension = Path::new(path).extension()?.to_str()?;
+            ContentType::from_extension(extension).map(|c| c.to_string())
+        })
+        .unwrap_or_else(|| conf.default_mime_type.clone())
+}
+
+fn too_many_members() -> status::Custom<String> {
+    status::Custom(Status::BadRequest, "Bundle contains too many files.".into())
+}
+
+/// Unpack a tar archive into temporary files, to be used as bundle members.
+///
+/// Only regular files are kept. Directories are implied by the paths of the
+/// files in them, and links are not followed.
+pub fn unpack_tar(
+    stream: impl Read,
+    conf: &Config,
+) -> Result<Vec<FormFile>, status::Custom<String>> {
+    let invalid = || status::Custom(Status::BadRequest, "Could not read tar archive.".into());
+    let mut archive = Archive::new(stream);
+    let mut files = vec![];
+    for entry in archive.entries().map_err(|_| invalid())? {
+        let mut entry = entry.map_err(|_| invalid())?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        if files.len() >= conf.restrictions.max_bundle_members.into() {
+            return Err(too_many_members());
+        }
+        let filename = entry
+            .path()
+            .map_err(|_| invalid())?
+            .to_str()
+            .map(str::to_string)
+            .ok_or_else(|| {
+                status::Custom(
+                    Status::BadRequest,
+                    "Tar archive contains a path which is not valid UTF-8.".into(),
+                )
+            })?;
+        let mut out_stream = BlobWriter::create(conf).map_err(|_| {
+            status::Custom(Status::InternalServerError, "Could not open file.".into())
+        })?;
+        let blob = copy(&mut entry, &mut out_stream)
+            .and_then(|_| out_stream.finish())
+            .map_err(|_| invalid())?;
+        files.push(FormFile {
+            filename: Some(filename),
+            mime_type: None,
+            blob,
+        });
+    }
+    Ok(files)
+}
+
+/// Check the files uploaded for a bundle, before anything is saved.
+pub fn prepare_members(
+    name: &str,
+    files: Vec<FormFile>,
+    conf: &Config,
+) -> Result<Vec<(BundleMember, NewBlob)>, status::Custom<String>> {
+    if files.is_empty() {
+        return Err(status::Custom(
+            Status::BadRequest,
+            "Bundles must contain at least one file.".into(),
+        ));
+    }
+    if files.len() > conf.restrictions.max_bundle_members.into() {
+        return Err(too_many_members());
+    }
+    let mut paths = HashSet::new();
+    let mut members = vec![];
+    for (position, file) in files.into_iter().enumerate() {
+        let path = clean_member_path(file.filename.as_deref().unwrap_or_default())?;
+        if !paths.insert(path.clone()) {
+            return Err(status::Custom(
+                Status::BadRequest,
+                format!("Bundle contains {:?} more than once.", path),
+            ));
+        }
+        if !file.mime_type.as_deref().map_or(true, is_valid_mime_type) {
+            return Err(status::Custom(
+                Status::BadRequest,
+                format!("Content-Type of {:?} is invalid.", path),
+            ));
+        }
+        let mime_type = member_mime_type(&file, &path, conf);
+        if !conf.restrictions.mime_type_allowed(&mime_type) {
+            return Err(status::Custom(
+                Status::Forbidden,
+                format!("Content-Type of {:?} is not allowed.", path),
+            ));
+        }
+        scan_blob(&file.blob, conf)?;
+        let member = BundleMember {
+            share_name: name.to_string(),
+            position: position as i32,
+            path,
+            mime_type,
+            size: file.blob.size as i64,
+            blob: file.blob.hash.clone(),
+        };
+        members.push((member, file.blob));
+    }
+    Ok(members)
+}
+
+/// Store the members of a bundle, once the share itself has been saved.
+pub fn save_members(
+    members: Vec<(BundleMember, NewBlob)>,
+    conf: &Config,
+    conn: &DbConn,
+) -> Result<(), status::Custom<String>> {
+    let mut rows = vec![];
+    for (member, blob) in members {
+        store_blob(blob, ShareKind::Bundle, conf, &conn.0)?;
+        rows.push(member);
+    }
+    if insert_into(bundle_members::table)
+        .values(&rows)
+        .execute(&conn.0)
+        .is_err()
+    {
+        // The contents were stored for these members, so they are released again.
+        for member in rows {
+            release_blob(&member.blob, &conf.upload_dir, &conn.0)?;
+        }
+        return Err(status::Custom(
+            Status::InternalServerError,
+            "Database error.".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Remove the members of a bundle, so that they can be replaced.
+pub fn delete_members(
+    name: &str,
+    conf: &Config,
+    conn: &DbConn,
+) -> Result<(), status::Custom<String>> {
+    let hashes = diesel::delete(bundle_members::table.filter(bundle_members::share_name.eq(name)))
+        .returning(bundle_members::blob)
+        .get_results::<String>(&conn.0)
+        .map_err(|_| status::Custom(Status::InternalServerError, "Database error.".into()))?;
+    for hash in hashes {
+        release_blob(&hash, &conf.upload_dir, &conn.0)?;
+    }
+    Ok(())
+}
+
+pub fn list_members(
+    name: &str,
+    conn: &DbConn,
+) -> Result<Vec<BundleMember>, status::Custom<String>> {
+    bundle_members::table
+        .filter(bundle_members::share_name.eq(name))
+        .order(bundle_members::position)
+        .load::<BundleMember>(&conn.0)
+        .map_err(|_| status::Custom(Status::InternalServerError, "Database error.".into()))
+}
+
+pub fn get_member(
+    name: &str,
+    path: &str,
+    conn: &DbConn,
+) -> Result<BundleMember, status::Custom<String>> {
+    bundle_members::table
+        .filter(bundle_members::share_name.eq(name))
+        .filter(bundle_members::path.eq(path))
+        .first::<BundleMember>(&conn.0)
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => {
+                status::Custom(Status::NotFound, "File not found in bundle.".into())
+            }
+            _ => status::Custom(Status::InternalServerError, "Database error.".into()),
+        })
+}
+
+/// How many chunks of an archive may be written ahead of the response.
+const ARCHIVE_PIPE_CHUNKS: usize = 16;
+
+/// Reads an archive as it is written by another thread.
+struct ArchivePipe {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl Read for ArchivePipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.position = 0;
+                }
+                // The writer has finished.
+                Err(_) => return Ok(0),
+            }
+        }
+        let read = (&self.chunk[self.position..]).read(buf)?;
+        self.position += read;
+        Ok(read)
+    }
+}
+
+/// Writes an archive to an `ArchivePipe`, waiting while the reader is behind.
+struct ArchivePipeWriter(SyncSender<io::Result<Vec<u8>>>);
+
+impl Write for ArchivePipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(Ok(buf.to_vec())).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Archive is no longer being read.",
+            )
+        })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+fn archive_path(name: &str, member: &BundleMember) -> String { format!("{}/{}", name, member.path) }
+
+fn write_zip(
+    name: &str,
+    members: &[BundleMember],
+    out_stream: impl Write + Seek,
+    conf: &Config,
+) -> io::Result<()> {
+    let mut zip = ZipWriter::new(out_stream);
+    for member in members {
+        zip.start_file(archive_path(name, member), FileOptions::default())?;
+        copy(&mut open_blob(&member.blob, conf)?, &mut zip)?;
+    }
+    zip.finish()?.flush()
+}
+
+fn write_tar_gz(
+    name: &str,
+    members: &[BundleMember],
+    out_stream: impl Write,
+    conf: &Config,
+) -> io::Result<()> {
+    let mut tar = tar::Builder::new(GzEncoder::new(out_stream, Compression::default()));
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    for member in members {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(member.size as u64);
+        header.set_mode(0o644);
+        header.set_mtime(now);
+        let source = open_blob(&member.blob, conf)?;
+        tar.append_data(&mut header, archive_path(name, member), source)?;
+    }
+    tar.into_inner()?.finish()?.flush()
+}
+
+/// Pack the members of a bundle into an archive.
+///
+/// Members are put in a directory named after the bundle. Zip archives need to
+/// be seeked while written, so they are written to a temporary file which is
+/// then read. Gzipped tar archives are written by another thread as they are
+/// read, so if that fails part way through the archive just stops early.
+pub fn write_archive(
+    name: &str,
+    members: Vec<BundleMember>,
+    format: ArchiveFormat,
+    conf: &Config,
+) -> io::Result<Box<dyn Read + Send>> {
+    match format {
+        ArchiveFormat::Zip => {
+            let (temp, file) = TempFile::create(conf)?;
+            write_zip(name, &members, BufWriter::new(file), conf)?;
+            Ok(Box::new(temp.open()?))
+        }
+        ArchiveFormat::TarGz => {
+            let (sender, receiver) = sync_channel(ARCHIVE_PIPE_CHUNKS);
+            let name = name.to_string();
+            let conf = conf.clone();
+            thread::spawn(move || {
+                let out_stream = BufWriter::new(ArchivePipeWriter(sender.clone()));
+                if let Err(e) = write_tar_gz(&name, &members, out_stream, &conf) {
+                    // This fails if the archive is no longer being read, which is fine.
+                    let _ = sender.send(Err(e));
+                }
+            });
+            Ok(Box::new(ArchivePipe {
+                receiver,
+                chunk: Vec::new(),
+                position: 0,
+            }))
+        }
+    }
+}
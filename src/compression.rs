@@ -0,0 +1,162 @@
+//! Tools for compressing share contents at rest and on the wire.
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::Deserialize;
+use std::io::{self, copy, Read, Write};
+
+/// A content coding which stored contents may be compressed with.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    Zstd,
+    Gzip,
+}
+
+/// Every encoding supported, in order of preference.
+pub const ENCODINGS: [Encoding; 2] = [Encoding::Zstd, Encoding::Gzip];
+
+impl Encoding {
+    /// The name of the encoding in Content-Encoding and Accept-Encoding.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "zstd" => Some(Encoding::Zstd),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            _ => None,
+        }
+    }
+
+    /// The extension given to stored files compressed with this encoding.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zst",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    /// Compress everything from a reader into a writer, returning the writer.
+    pub fn encode<W: Write>(
+        self,
+        source: &mut impl Read,
+        dest: W,
+        level: Option<i32>,
+    ) -> io::Result<W> {
+        match self {
+            Encoding::Zstd => {
+                // Level 0 means zstd's default level.
+                let mut encoder = zstd::stream::write::Encoder::new(dest, level.unwrap_or(0))?;
+                copy(source, &mut encoder)?;
+                encoder.finish()
+            }
+            Encoding::Gzip => {
+                let level = match level {
+                    Some(level) => flate2::Compression::new(level.clamp(0, 9) as u32),
+                    None => flate2::Compression::default(),
+                };
+                let mut encoder = GzEncoder::new(dest, level);
+                copy(source, &mut encoder)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Wrap a reader so that it gives the decompressed contents.
+    pub fn decode<'a>(self, source: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+        match self {
+            Encoding::Zstd => Ok(Box::new(zstd::stream::read::Decoder::new(source)?)),
+            Encoding::Gzip => Ok(Box::new(GzDecoder::new(source))),
+        }
+    }
+}
+
+/// Parse an Accept-Encoding header, giving the supported encodings it allows.
+pub fn parse_accept_encoding(raw: &str) -> Vec<Encoding> {
+    let mut accepted = vec![];
+    let mut rejected = vec![];
+    for item in raw.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim();
+        let refused = parts.any(|param| match param.split_once('=') {
+            Some((key, value)) => {
+                key.trim().eq_ignore_ascii_case("q")
+                    && value.trim().parse::<f32>().map_or(false, |q| q <= 0.0)
+            }
+            None => false,
+        });
+        let encodings = match coding {
+            "*" => ENCODINGS.to_vec(),
+            _ => Encoding::from_name(coding).into_iter().collect(),
+        };
+        if refused {
+            rejected.extend(encodings);
+        } else {
+            accepted.extend(encodings);
+        }
+    }
+    accepted.retain(|encoding| !rejected.contains(encoding));
+    accepted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_contents() {
+        let contents = "compressible ".repeat(1000);
+        for &encoding in &ENCODINGS {
+            for &level in &[None, Some(1), Some(19)] {
+                let compressed = encoding
+                    .encode(&mut contents.as_bytes(), vec![], level)
+                    .unwrap();
+                assert!(compressed.len() < contents.len());
+                let mut decompressed = String::new();
+                encoding
+                    .decode(&compressed[..])
+                    .unwrap()
+                    .read_to_string(&mut decompressed)
+                    .unwrap();
+                assert_eq!(decompressed, contents);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_contents() {
+        for &encoding in &ENCODINGS {
+            let mut decompressed = vec![];
+            let result = encoding
+                .decode(&b"not compressed at all"[..])
+                .and_then(|mut decoder| decoder.read_to_end(&mut decompressed));
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn parses_accept_encoding() {
+        assert_eq!(parse_accept_encoding("gzip"), vec![Encoding::Gzip]);
+        assert_eq!(
+            parse_accept_encoding("br, X-GZIP;q=0.5, zstd"),
+            vec![Encoding::Gzip, Encoding::Zstd]
+        );
+        assert_eq!(parse_accept_encoding("*"), ENCODINGS.to_vec());
+        assert_eq!(parse_accept_encoding(""), vec![]);
+        assert_eq!(parse_accept_encoding("identity, br"), vec![]);
+    }
+
+    #[test]
+    fn honours_refused_encodings() {
+        assert_eq!(parse_accept_encoding("*, zstd;q=0"), vec![Encoding::Gzip]);
+        assert_eq!(parse_accept_encoding("gzip;q=0.0, gzip"), vec![]);
+        assert_eq!(
+            parse_accept_encoding("gzip; Q = 0.001"),
+            vec![Encoding::Gzip]
+        );
+    }
+}
@@ -1,15 +1,21 @@
 //! Manages configuration of the server and Rocket.
+use crate::compression::Encoding;
+use crate::encryption::{parse_key, Key, MAX_KEY_ID_LENGTH};
+use crate::links::HostPattern;
+use crate::mime::MimePattern;
+use crate::models::{RedirectCode, ShareKind};
 use byte_unit::Byte;
 use rocket::config::{Environment, Limits};
 use rocket::http::Status;
 use rocket::response::status;
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::ffi::OsString;
-use std::fs::{create_dir_all, File};
+use std::fs::{create_dir_all, read_to_string, File};
 use std::io::Read;
 use std::path::PathBuf;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use std::{env, process};
 
@@ -24,8 +30,21 @@ fn default_random_name_length() -> RwLock<usize> { RwLock::new(8) }
 fn default_random_name_attempt_limit() -> u8 { 3 }
 fn default_max_upload_size() -> Byte { Byte::from_str("2 MB").unwrap() }
 fn default_max_link_length() -> u16 { 255 }
+fn default_max_revisions() -> u16 { 10 }
+fn default_max_bundle_members() -> u16 { 100 }
+fn default_sniff_mime_types() -> bool { true }
 fn default_allowed_link_schemes() -> Vec<String> { vec!["http".into(), "https".into()] }
-fn default_disallowed_mime_types() -> Vec<String> { vec!["text/html".into()] }
+fn default_allow_ip_link_hosts() -> bool { true }
+fn default_link_redirect_code() -> RedirectCode { RedirectCode::TemporaryRedirect }
+fn default_disallowed_mime_types() -> Vec<MimePattern> {
+    vec![MimePattern::try_from("text/html".to_string()).unwrap()]
+}
+fn default_attachment_mime_types() -> Vec<MimePattern> {
+    ["text/html", "*/*+xml", "application/xml", "text/xml"]
+        .iter()
+        .map(|raw| MimePattern::try_from(raw.to_string()).unwrap())
+        .collect()
+}
 fn default_bind_address() -> String { "127.0.0.1".into() }
 fn default_bind_port() -> u16 { 8000 }
 fn default_db_host() -> String { "127.0.0.1".into() }
@@ -33,6 +52,13 @@ fn default_db_port() -> u16 { 5432 }
 fn default_db_user() -> String { "shareit".into() }
 fn default_db_name() -> String { "shareit".into() }
 fn default_upload_dir() -> PathBuf { "/var/shareit/shares/".into() }
+fn default_highlighting_theme() -> String { "InspiredGitHub".into() }
+fn default_render_markdown() -> bool { true }
+fn default_compressed_kinds() -> Vec<String> { vec!["paste".into()] }
+fn default_compression_min_size() -> Byte { Byte::from_str("1 KB").unwrap() }
+fn default_thumbnail_max_expansion() -> u64 { 32 }
+fn default_scan_timeout() -> Duration { Duration::from_secs(30) }
+fn default_count_clicks() -> bool { true }
 
 fn default_passwords() -> HashMap<String, Vec<Permission>> {
     HashMap::from([(
@@ -53,7 +79,7 @@ fn default_highlighting_languages() -> Vec<String> {
         .collect()
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct Config {
     #[serde(default)]
     pub frontend_path: Option<PathBuf>,
@@ -65,6 +91,17 @@ pub struct Config {
     pub default_highlighting_language: String,
     #[serde(default = "default_mime_type")]
     pub default_mime_type: String,
+    /// Remove metadata from every uploaded image, not just when asked to.
+    #[serde(default)]
+    pub strip_metadata: bool,
+    /// Show browsers a preview page before following links, unless the link
+    /// was created without one.
+    #[serde(default)]
+    pub link_preview: bool,
+    /// The status code to redirect to links with, unless the link was created
+    /// with one.
+    #[serde(default = "default_link_redirect_code")]
+    pub link_redirect_code: RedirectCode,
     #[serde(with = "humantime_serde", default = "default_expiry_check_interval")]
     pub expiry_check_interval: Duration,
     #[serde(default = "default_passwords")]
@@ -73,20 +110,34 @@ pub struct Config {
     pub names: NamesConfig,
     #[serde(default)]
     pub restrictions: RestrictionsConfig,
+    #[serde(default)]
+    pub rendering: RenderingConfig,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+    #[serde(default)]
+    pub thumbnails: ThumbnailConfig,
+    #[serde(default)]
+    pub scanning: ScanningConfig,
+    #[serde(default)]
+    pub analytics: AnalyticsConfig,
     pub network: NetworkConfig,
     pub database: DatabaseConfig,
 }
 
-#[derive(Deserialize, PartialEq, Eq)]
+#[derive(Clone, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum Permission {
     CreateAny,
     CreateLink,
     CreateFile,
     CreatePaste,
+    CreateBundle,
     UpdateOwn,
     UpdateAny,
     CustomName,
+    ReadPrivate,
 }
 
 #[derive(Deserialize)]
@@ -120,6 +171,20 @@ impl NamesConfig {
     }
 }
 
+/// Copies the random name length as it is now, so a copy shouldn't be used to
+/// generate names.
+impl Clone for NamesConfig {
+    fn clone(&self) -> Self {
+        let random_length = self.random_length.read().map_or(0, |value| *value);
+        NamesConfig {
+            min_length: self.min_length,
+            max_length: self.max_length,
+            random_length: RwLock::new(random_length),
+            random_attempt_limit: self.random_attempt_limit,
+        }
+    }
+}
+
 impl Default for NamesConfig {
     fn default() -> NamesConfig {
         NamesConfig {
@@ -131,7 +196,7 @@ impl Default for NamesConfig {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct RestrictionsConfig {
     #[serde(default = "default_max_upload_size")]
     pub max_upload_size: Byte,
@@ -141,11 +206,49 @@ pub struct RestrictionsConfig {
     #[serde(with = "humantime_serde", default)]
     pub max_expiry_time: Option<Duration>,
     #[serde(default)]
-    pub allowed_mime_types: Vec<String>,
+    pub allowed_mime_types: Vec<MimePattern>,
     #[serde(default = "default_disallowed_mime_types")]
-    pub disallowed_mime_types: Vec<String>,
+    pub disallowed_mime_types: Vec<MimePattern>,
+    /// Guess the MIME type of files from their contents if none is given.
+    #[serde(default = "default_sniff_mime_types")]
+    pub sniff_mime_types: bool,
+    /// Files of these types are always downloaded rather than shown.
+    #[serde(default = "default_attachment_mime_types")]
+    pub attachment_mime_types: Vec<MimePattern>,
+    /// Reject files whose contents don't match the MIME type given, or are of
+    /// a type which is not allowed.
+    #[serde(default)]
+    pub strict_mime_types: bool,
     #[serde(default = "default_allowed_link_schemes")]
     pub allowed_link_schemes: Vec<String>,
+    /// If not empty, links may only be made to hosts matching these patterns.
+    #[serde(default)]
+    pub allowed_link_hosts: Vec<HostPattern>,
+    /// Links may not be made to hosts matching these patterns. Ignored if
+    /// allowed_link_hosts is not empty.
+    #[serde(default)]
+    pub disallowed_link_hosts: Vec<HostPattern>,
+    /// Allow links to IP addresses rather than names.
+    #[serde(default = "default_allow_ip_link_hosts")]
+    pub allow_ip_link_hosts: bool,
+    /// Allow links to localhost and private network addresses.
+    #[serde(default)]
+    pub allow_private_link_hosts: bool,
+    #[serde(default = "default_max_revisions")]
+    pub max_revisions: u16,
+    #[serde(default = "default_max_bundle_members")]
+    pub max_bundle_members: u16,
+}
+
+impl RestrictionsConfig {
+    pub fn mime_type_allowed(&self, mime_type: &str) -> bool {
+        let matches = |pattern: &MimePattern| pattern.matches(mime_type);
+        if !self.allowed_mime_types.is_empty() {
+            self.allowed_mime_types.iter().any(matches)
+        } else {
+            !self.disallowed_mime_types.iter().any(matches)
+        }
+    }
 }
 
 impl Default for RestrictionsConfig {
@@ -156,21 +259,226 @@ impl Default for RestrictionsConfig {
             max_expiry_time: None,
             allowed_mime_types: vec![],
             disallowed_mime_types: vec![],
+            sniff_mime_types: default_sniff_mime_types(),
+            attachment_mime_types: default_attachment_mime_types(),
+            strict_mime_types: false,
             allowed_link_schemes: default_allowed_link_schemes(),
+            allowed_link_hosts: vec![],
+            disallowed_link_hosts: vec![],
+            allow_ip_link_hosts: default_allow_ip_link_hosts(),
+            allow_private_link_hosts: false,
+            max_revisions: default_max_revisions(),
+            max_bundle_members: default_max_bundle_members(),
         }
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
+pub struct RenderingConfig {
+    #[serde(default = "default_highlighting_theme")]
+    pub default_theme: String,
+    #[serde(default = "default_render_markdown")]
+    pub markdown: bool,
+}
+
+impl Default for RenderingConfig {
+    fn default() -> Self {
+        RenderingConfig {
+            default_theme: default_highlighting_theme(),
+            markdown: default_render_markdown(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct CompressionConfig {
+    #[serde(default)]
+    pub algorithm: Option<Encoding>,
+    #[serde(default)]
+    pub level: Option<i32>,
+    #[serde(default = "default_compressed_kinds")]
+    pub kinds: Vec<String>,
+    #[serde(default = "default_compression_min_size")]
+    pub min_size: Byte,
+}
+
+impl CompressionConfig {
+    /// Choose how new contents of a share should be compressed, if at all.
+    pub fn choose(&self, kind: ShareKind, size: u64) -> Option<Encoding> {
+        let wanted =
+            self.kinds.iter().any(|name| name == kind.name()) && size >= self.min_size.get_bytes();
+        self.algorithm.filter(|_| wanted)
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            algorithm: None,
+            level: None,
+            kinds: default_compressed_kinds(),
+            min_size: default_compression_min_size(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ThumbnailConfig {
+    /// The sizes to make thumbnails of images at, as the longest side in pixels.
+    #[serde(default)]
+    pub sizes: Vec<u32>,
+    /// How many times larger than the maximum upload size an image may be once
+    /// decoded, so that small files can't use up all the memory.
+    #[serde(default = "default_thumbnail_max_expansion")]
+    pub max_expansion: u64,
+}
+
+impl ThumbnailConfig {
+    /// The most memory which may be used to decode an image, in bytes.
+    pub fn max_decoded_size(&self, restrictions: &RestrictionsConfig) -> u64 {
+        restrictions
+            .max_upload_size
+            .get_bytes()
+            .saturating_mul(self.max_expansion)
+    }
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> Self {
+        ThumbnailConfig {
+            sizes: vec![],
+            max_expansion: default_thumbnail_max_expansion(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ScanningConfig {
+    /// The scanner to check uploads with, if any.
+    #[serde(default)]
+    pub scanner: Option<ScannerConfig>,
+    /// How long to wait for clamd to reply.
+    #[serde(with = "humantime_serde", default = "default_scan_timeout")]
+    pub timeout: Duration,
+}
+
+impl Default for ScanningConfig {
+    fn default() -> Self {
+        ScanningConfig {
+            scanner: None,
+            timeout: default_scan_timeout(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScannerConfig {
+    /// The path of clamd's Unix socket.
+    ClamdSocket(PathBuf),
+    /// The host and port clamd is listening on.
+    ClamdAddress(String),
+    /// A command to run, with the path of the file added to the end.
+    Command(Vec<String>),
+}
+
+#[derive(Clone, Deserialize)]
+pub struct AnalyticsConfig {
+    /// Count how many times each link is followed each day.
+    #[serde(default = "default_count_clicks")]
+    pub count_clicks: bool,
+    /// Also count clicks by referrer host, browser family and country.
+    #[serde(default)]
+    pub details: bool,
+    /// A MaxMind country database to look up the countries of clients in.
+    #[serde(default)]
+    geoip_database: Option<PathBuf>,
+    #[serde(skip)]
+    pub geoip: Option<Arc<maxminddb::Reader<Vec<u8>>>>,
+}
+
+impl AnalyticsConfig {
+    fn load_geoip(&mut self) -> Result<(), String> {
+        if let Some(path) = &self.geoip_database {
+            let reader = maxminddb::Reader::open_readfile(path)
+                .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+            self.geoip = Some(Arc::new(reader));
+        }
+        Ok(())
+    }
+}
+
+impl Default for AnalyticsConfig {
+    fn default() -> Self {
+        AnalyticsConfig {
+            count_clicks: default_count_clicks(),
+            details: false,
+            geoip_database: None,
+            geoip: None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Default)]
+pub struct EncryptionConfig {
+    #[serde(default)]
+    pub key_id: Option<String>,
+    #[serde(default)]
+    keys: HashMap<String, KeySource>,
+    #[serde(skip)]
+    pub loaded_keys: HashMap<String, Key>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum KeySource {
+    Key(String),
+    KeyFile(PathBuf),
+}
+
+impl EncryptionConfig {
+    fn load_keys(&mut self) -> Result<(), String> {
+        for (id, source) in &self.keys {
+            if id.is_empty() || id.len() > MAX_KEY_ID_LENGTH {
+                return Err(format!("Key ID {} must be 1 to 255 bytes long.", id));
+            }
+            let raw = match source {
+                KeySource::Key(raw) => raw.clone(),
+                KeySource::KeyFile(path) => read_to_string(path)
+                    .map_err(|e| format!("Could not read {}: {}", path.display(), e))?,
+            };
+            let key = parse_key(&raw).map_err(|e| format!("Key {} is invalid: {}", id, e))?;
+            self.loaded_keys.insert(id.clone(), key);
+        }
+        match &self.key_id {
+            Some(id) if !self.loaded_keys.contains_key(id) => {
+                Err(format!("Key {} is not configured.", id))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The ID and value of the key to encrypt new contents with, if any.
+    pub fn current_key(&self) -> Option<(&str, &Key)> {
+        let id = self.key_id.as_ref()?;
+        self.loaded_keys.get(id).map(|key| (id.as_str(), key))
+    }
+}
+
+#[derive(Clone, Deserialize)]
 pub struct NetworkConfig {
     pub host: url::Url,
+    /// A separate host to serve uploaded contents from, so that they can't
+    /// access the API or frontend.
+    #[serde(default)]
+    pub content_host: Option<url::Url>,
     #[serde(default = "default_bind_address")]
     pub address: String,
     #[serde(default = "default_bind_port")]
     pub port: u16,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct DatabaseConfig {
     pub pass: String,
     #[serde(default = "default_db_host")]
@@ -187,7 +495,10 @@ impl Config {
     pub fn load() -> Self {
         let args: Vec<OsString> = env::args_os().collect();
         if args.len() < 2 {
-            eprintln!("Usage: {} <config file>", args[0].to_string_lossy());
+            eprintln!(
+                "Usage: {} <config file> [rotate-keys]",
+                args[0].to_string_lossy()
+            );
             process::exit(1);
         }
         let mut file =
@@ -195,7 +506,15 @@ impl Config {
         let mut raw = String::new();
         file.read_to_string(&mut raw)
             .expect("Could not read or decode config file");
-        let config: Config = toml::from_str(&raw).expect("Could not parse config file");
+        let mut config: Config = toml::from_str(&raw).expect("Could not parse config file");
+        if let Err(e) = config.encryption.load_keys() {
+            eprintln!("Could not load encryption keys: {}", e);
+            process::exit(1);
+        }
+        if let Err(e) = config.analytics.load_geoip() {
+            eprintln!("Could not load GeoIP database: {}", e);
+            process::exit(1);
+        }
         create_dir_all(&config.upload_dir).expect("Could not create upload directory");
         config
     }
@@ -0,0 +1,88 @@
+//! Tools for serving uploaded contents safely, so that they can't run scripts
+//! with access to the API or the frontend.
+use crate::config::Config;
+use crate::headers::HeaderParams;
+use rocket::http::uri::Origin;
+use rocket::http::ContentType;
+use rocket::request::Request;
+use rocket::response::ResponseBuilder;
+
+/// Stops browsers from running scripts, plugins, forms or frames in uploaded
+/// contents, while still allowing eg. SVG images to show their styles.
+const CONTENT_SECURITY_POLICY: &str = concat!(
+    "sandbox; default-src 'none'; img-src 'self' data:; ",
+    "media-src 'self'; style-src 'unsafe-inline'",
+);
+
+/// Stops pages generated by the server, such as highlighted pastes and link
+/// previews, from running scripts or loading anything but images, and only
+/// lets their forms submit back to this server.
+const PAGE_CONTENT_SECURITY_POLICY: &str = concat!(
+    "default-src 'none'; img-src * data:; style-src 'unsafe-inline'; ",
+    "form-action 'self'; base-uri 'none'; frame-ancestors 'none'",
+);
+
+/// Whether a request was made to the user content host, given its Host header.
+fn on_content_host(host: Option<&str>, conf: &Config) -> bool {
+    let content_host = match &conf.network.content_host {
+        Some(url) => url,
+        None => return false,
+    };
+    let expected = match (content_host.host_str(), content_host.port()) {
+        (Some(name), Some(port)) => format!("{}:{}", name, port),
+        (Some(name), None) => name.to_string(),
+        (None, _) => return false,
+    };
+    host.map_or(false, |host| host.eq_ignore_ascii_case(&expected))
+}
+
+/// The URL on the user content host to redirect a request for contents to, if
+/// they shouldn't be served from this host.
+///
+/// Requests using the Authorization header are served here, since browsers
+/// drop it when following a redirect to another origin.
+pub fn content_redirect(uri: &Origin, headers: &HeaderParams, conf: &Config) -> Option<String> {
+    let content_host = conf.network.content_host.as_ref()?;
+    if headers.has_auth() || on_content_host(headers.host.as_deref(), conf) {
+        return None;
+    }
+    let mut url = content_host.join(uri.path().trim_start_matches('/')).ok()?;
+    url.set_query(uri.query());
+    Some(url.into())
+}
+
+/// Send a page generated by the server as HTML, with a policy which stops it
+/// from running anything it was tricked into containing.
+pub fn add_page_headers(response: &mut ResponseBuilder) {
+    response
+        .header(ContentType::HTML)
+        .raw_header("X-Content-Type-Options", "nosniff")
+        .raw_header("Content-Security-Policy", PAGE_CONTENT_SECURITY_POLICY);
+}
+
+/// Stop browsers from treating uploaded contents as part of the site, or as
+/// anything other than the type they were served with.
+pub fn add_sandbox_headers(response: &mut ResponseBuilder, mime_type: &str, conf: &Config) {
+    response
+        .raw_header("X-Content-Type-Options", "nosniff")
+        .raw_header("Content-Security-Policy", CONTENT_SECURITY_POLICY);
+    let risky = conf
+        .restrictions
+        .attachment_mime_types
+        .iter()
+        .any(|pattern| pattern.matches(mime_type));
+    if risky {
+        response.raw_header("Content-Disposition", "attachment");
+    }
+}
+
+/// Let the frontend read responses from the user content host.
+///
+/// Nothing served from that host depends on cookies, so any origin is allowed.
+pub fn add_content_host_headers(response: &mut ResponseBuilder, request: &Request, conf: &Config) {
+    if on_content_host(request.headers().get_one("Host"), conf) {
+        response
+            .raw_header("Access-Control-Allow-Origin", "*")
+            .raw_header("Access-Control-Expose-Headers", "*");
+    }
+}
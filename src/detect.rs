@@ -0,0 +1,470 @@
+//! Tools for guessing the highlighting language of a paste.
+use crate::config::Config;
+use rocket::http::Status;
+use rocket::response::status;
+use std::io::Read;
+
+/// The highlighting language which asks for the language to be detected.
+pub const AUTO_LANGUAGE: &str = "auto";
+
+/// The language to fall back to if detection fails.
+const FALLBACK_LANGUAGE: &str = "plaintext";
+
+/// How many bytes from the start of a paste are used for detection.
+pub const SAMPLE_LENGTH: usize = 8192;
+
+/// Number of lines at the start and end of a paste to search for modelines.
+const MODELINE_SEARCH_LINES: usize = 5;
+
+/// File extensions (and some complete file names) mapped to languages.
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("bash", "bash"),
+    ("c", "c"),
+    ("cc", "cpp"),
+    ("cfg", "ini"),
+    ("cjs", "javascript"),
+    ("clj", "clojure"),
+    ("cmake", "cmake"),
+    ("conf", "ini"),
+    ("cpp", "cpp"),
+    ("cs", "csharp"),
+    ("css", "css"),
+    ("cxx", "cpp"),
+    ("dart", "dart"),
+    ("diff", "diff"),
+    ("dockerfile", "dockerfile"),
+    ("erl", "erlang"),
+    ("ex", "elixir"),
+    ("exs", "elixir"),
+    ("fs", "fsharp"),
+    ("go", "go"),
+    ("gradle", "gradle"),
+    ("groovy", "groovy"),
+    ("h", "c"),
+    ("hh", "cpp"),
+    ("hpp", "cpp"),
+    ("hs", "haskell"),
+    ("htm", "xml"),
+    ("html", "xml"),
+    ("ini", "ini"),
+    ("java", "java"),
+    ("jl", "julia"),
+    ("js", "javascript"),
+    ("json", "json"),
+    ("jsx", "javascript"),
+    ("kt", "kotlin"),
+    ("kts", "kotlin"),
+    ("less", "less"),
+    ("log", "plaintext"),
+    ("lua", "lua"),
+    ("m", "objectivec"),
+    ("makefile", "makefile"),
+    ("markdown", "markdown"),
+    ("md", "markdown"),
+    ("mjs", "javascript"),
+    ("mk", "makefile"),
+    ("ml", "ocaml"),
+    ("nim", "nim"),
+    ("nix", "nix"),
+    ("patch", "diff"),
+    ("php", "php"),
+    ("pl", "perl"),
+    ("pm", "perl"),
+    ("properties", "properties"),
+    ("proto", "protobuf"),
+    ("ps1", "powershell"),
+    ("py", "python"),
+    ("pyw", "python"),
+    ("r", "r"),
+    ("rb", "ruby"),
+    ("rs", "rust"),
+    ("scala", "scala"),
+    ("scss", "scss"),
+    ("sh", "bash"),
+    ("sql", "sql"),
+    ("svg", "xml"),
+    ("swift", "swift"),
+    ("tex", "latex"),
+    ("toml", "ini"),
+    ("ts", "typescript"),
+    ("tsx", "typescript"),
+    ("txt", "plaintext"),
+    ("vb", "vbnet"),
+    ("vim", "vim"),
+    ("xhtml", "xml"),
+    ("xml", "xml"),
+    ("yaml", "yaml"),
+    ("yml", "yaml"),
+    ("zsh", "bash"),
+];
+
+/// Interpreters (from shebangs) and editor mode names mapped to languages.
+const ALIASES: &[(&str, &str)] = &[
+    ("ash", "bash"),
+    ("c++", "cpp"),
+    ("dash", "bash"),
+    ("deno", "typescript"),
+    ("emacs-lisp", "lisp"),
+    ("gawk", "awk"),
+    ("js2", "javascript"),
+    ("ksh", "bash"),
+    ("make", "makefile"),
+    ("node", "javascript"),
+    ("nodejs", "javascript"),
+    ("pwsh", "powershell"),
+    ("pypy", "python"),
+    ("rscript", "r"),
+    ("shell-script", "bash"),
+    ("tclsh", "tcl"),
+    ("text", "plaintext"),
+    ("ts-node", "typescript"),
+];
+
+/// Checks on the contents of a paste, in order of precedence.
+const HEURISTICS: &[(&str, fn(&str) -> bool)] = &[
+    ("php", |s| s.starts_with("<?php")),
+    ("xml", |s| {
+        let start = s.trim_start().to_lowercase();
+        start.starts_with("<?xml")
+            || start.starts_with("<!doctype html")
+            || start.starts_with("<html")
+    }),
+    ("diff", |s| {
+        s.starts_with("diff ")
+            || (has_line_starting(s, "--- ")
+                && has_line_starting(s, "+++ ")
+                && has_line_starting(s, "@@ "))
+    }),
+    ("json", |s| {
+        let mut chars = s.trim_start().chars();
+        matches!(chars.next(), Some('{') | Some('['))
+            && matches!(
+                chars.find(|c| !c.is_whitespace()),
+                Some('"') | Some('{') | Some('[') | Some('}') | Some(']')
+            )
+    }),
+    ("dockerfile", |s| {
+        first_code_line(s, "#").map_or(false, |line| line.starts_with("FROM "))
+    }),
+    ("cpp", |s| {
+        has_line_starting(s, "#include")
+            && (s.contains("std::")
+                || s.contains("namespace ")
+                || s.contains("template<")
+                || s.contains("class "))
+    }),
+    ("c", |s| has_line_starting(s, "#include")),
+    ("rust", |s| {
+        s.contains("fn main(")
+            || has_line_starting(s, "use std::")
+            || s.contains("let mut ")
+            || has_line_starting(s, "pub fn ")
+    }),
+    ("go", |s| {
+        has_line_starting(s, "package ") && has_line_starting(s, "func ")
+    }),
+    ("java", |s| {
+        has_line_starting(s, "import java.") || s.contains("public static void main(")
+    }),
+    ("csharp", |s| has_line_starting(s, "using System")),
+    ("python", |s| {
+        s.contains("if __name__ ==")
+            || ((has_line_starting(s, "def ") || has_line_starting(s, "class "))
+                && s.contains("):"))
+            || (has_line_starting(s, "from ") && s.contains(" import "))
+    }),
+    ("typescript", |s| {
+        has_line_starting(s, "interface ") || s.contains(": string") || s.contains(": number")
+    }),
+    ("javascript", |s| {
+        s.contains("console.log(")
+            || s.contains("require(")
+            || s.contains("module.exports")
+            || s.contains("=> {")
+            || has_line_starting(s, "function ")
+    }),
+    ("sql", |s| {
+        first_code_line(s, "--").map_or(false, |line| {
+            let line = line.to_uppercase();
+            [
+                "SELECT ",
+                "INSERT INTO ",
+                "UPDATE ",
+                "DELETE FROM ",
+                "CREATE ",
+                "ALTER ",
+                "DROP ",
+                "WITH ",
+            ]
+            .iter()
+            .any(|keyword| line.starts_with(keyword))
+        })
+    }),
+    ("yaml", |s| s.starts_with("---\n") || s.starts_with("%YAML")),
+    ("ini", |s| {
+        first_code_line(s, "#").map_or(false, |line| {
+            line.starts_with('[') && line.trim_end().ends_with(']')
+        })
+    }),
+    ("makefile", |s| {
+        s.lines().any(|line| line.starts_with('\t')) && s.lines().any(is_make_rule)
+    }),
+    ("css", |s| {
+        s.lines().any(|line| line.trim_end().ends_with('{')) && s.lines().any(is_css_declaration)
+    }),
+    ("markdown", |s| {
+        s.contains("```") || has_line_starting(s, "## ") || (s.contains("](") && s.contains('['))
+    }),
+    ("bash", |s| {
+        has_line_starting(s, "$ ")
+            || has_line_starting(s, "sudo ")
+            || has_line_starting(s, "export ")
+    }),
+];
+
+fn has_line_starting(sample: &str, prefix: &str) -> bool {
+    sample.lines().any(|line| line.starts_with(prefix))
+}
+
+/// Get the first non-empty line which is not a comment.
+fn first_code_line<'a>(sample: &'a str, comment: &str) -> Option<&'a str> {
+    sample
+        .lines()
+        .map(|line| line.trim())
+        .find(|line| !line.is_empty() && !line.starts_with(comment))
+}
+
+fn is_make_rule(line: &str) -> bool {
+    match line.split_once(':') {
+        Some((target, rest)) => {
+            !target.is_empty()
+                && !rest.starts_with('=')
+                && target
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "._-/$() ".contains(c))
+        }
+        None => false,
+    }
+}
+
+fn is_css_declaration(line: &str) -> bool {
+    let line = line.trim();
+    match line.split_once(':') {
+        Some((property, _)) => {
+            line.ends_with(';')
+                && !property.is_empty()
+                && property.chars().all(|c| c.is_ascii_lowercase() || c == '-')
+        }
+        None => false,
+    }
+}
+
+fn lookup(table: &[(&str, &'static str)], key: &str) -> Option<&'static str> {
+    table
+        .iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, language)| *language)
+}
+
+/// Turn a file extension, interpreter or editor mode name into a language.
+fn canonical_language(name: &str, languages: &[String]) -> Option<String> {
+    let name = name.to_lowercase();
+    if languages.contains(&name) {
+        return Some(name);
+    }
+    lookup(EXTENSIONS, &name)
+        .or_else(|| lookup(ALIASES, &name))
+        .map(|language| language.to_string())
+        .filter(|language| languages.contains(language))
+}
+
+fn from_filename(filename: &str, languages: &[String]) -> Option<String> {
+    let filename = filename.to_lowercase();
+    let extension = match filename.rsplit_once('.') {
+        Some((_, extension)) => extension,
+        None => filename.as_str(),
+    };
+    lookup(EXTENSIONS, extension)
+        .map(|language| language.to_string())
+        .filter(|language| languages.contains(language))
+}
+
+fn from_shebang(sample: &str, languages: &[String]) -> Option<String> {
+    let line = sample.lines().next()?.strip_prefix("#!")?;
+    let mut words = line.split_whitespace();
+    let mut interpreter = words.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        interpreter = words.find(|word| !word.starts_with('-'))?;
+    }
+    // Strip version numbers, eg. "python3.9" becomes "python".
+    let interpreter = interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    canonical_language(interpreter, languages)
+}
+
+fn emacs_mode(line: &str) -> Option<&str> {
+    let (_, rest) = line.split_once("-*-")?;
+    let (inner, _) = rest.split_once("-*-")?;
+    let inner = inner.trim();
+    if !inner.contains(':') {
+        return Some(inner);
+    }
+    inner.split(';').find_map(|part| {
+        let (key, value) = part.split_once(':')?;
+        if key.trim().eq_ignore_ascii_case("mode") {
+            Some(value.trim())
+        } else {
+            None
+        }
+    })
+}
+
+fn vim_filetype(line: &str) -> Option<&str> {
+    let start = ["vim:", "vi:", "ex:"]
+        .iter()
+        .find_map(|marker| line.find(marker).map(|index| index + marker.len()))?;
+    line[start..]
+        .split(|c: char| c == ':' || c.is_whitespace())
+        .find_map(|option| {
+            option
+                .strip_prefix("ft=")
+                .or_else(|| option.strip_prefix("filetype="))
+                .or_else(|| option.strip_prefix("syntax="))
+        })
+}
+
+fn from_modeline(sample: &str, languages: &[String]) -> Option<String> {
+    let lines: Vec<&str> = sample.lines().collect();
+    let tail_start = lines.len().saturating_sub(MODELINE_SEARCH_LINES);
+    lines
+        .iter()
+        .take(MODELINE_SEARCH_LINES)
+        .chain(lines.iter().skip(tail_start))
+        .find_map(|line| emacs_mode(line).or_else(|| vim_filetype(line)))
+        .and_then(|mode| canonical_language(mode, languages))
+}
+
+fn from_contents(sample: &str, languages: &[String]) -> Option<String> {
+    HEURISTICS
+        .iter()
+        .find(|(language, check)| languages.contains(&language.to_string()) && check(sample))
+        .map(|(language, _)| language.to_string())
+}
+
+/// Guess the language of a paste from the start of its contents and,
+/// optionally, its file name.
+pub fn detect_language(
+    sample: &str,
+    filename: Option<&str>,
+    languages: &[String],
+) -> Option<String> {
+    filename
+        .and_then(|filename| from_filename(filename, languages))
+        .or_else(|| from_shebang(sample, languages))
+        .or_else(|| from_modeline(sample, languages))
+        .or_else(|| from_contents(sample, languages))
+        .or_else(|| Some(FALLBACK_LANGUAGE.to_string()).filter(|l| languages.contains(l)))
+}
+
+/// Resolve a requested highlighting language.
+///
+/// Returns the language to store and whether it was detected.
+pub fn resolve_language(
+    requested: String,
+    sample: &str,
+    filename: Option<&str>,
+    conf: &Config,
+) -> (String, bool) {
+    if requested != AUTO_LANGUAGE {
+        return (requested, false);
+    }
+    match detect_language(sample, filename, &conf.highlighting_languages) {
+        Some(language) => (language, true),
+        None => (requested, false),
+    }
+}
+
+/// Read the start of an existing paste, for detecting its language.
+pub fn read_sample(source: impl Read) -> Result<String, status::Custom<String>> {
+    let mut raw = Vec::with_capacity(SAMPLE_LENGTH);
+    source
+        .take(SAMPLE_LENGTH as u64)
+        .read_to_end(&mut raw)
+        .map_err(|_| status::Custom(Status::InternalServerError, "Could not read file.".into()))?;
+    Ok(String::from_utf8_lossy(&raw).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn languages(names: &[&str]) -> Vec<String> { names.iter().map(|s| s.to_string()).collect() }
+
+    #[test]
+    fn detects_from_filename() {
+        let languages = languages(&["rust", "python"]);
+        assert_eq!(
+            detect_language("", Some("main.RS"), &languages),
+            Some("rust".into())
+        );
+        assert_eq!(detect_language("", Some("notes.txt"), &languages), None);
+    }
+
+    #[test]
+    fn detects_from_shebang() {
+        let languages = languages(&["python", "bash"]);
+        let sample = "#!/usr/bin/env -S python3.9\nprint(1)\n";
+        assert_eq!(
+            detect_language(sample, None, &languages),
+            Some("python".into())
+        );
+        assert_eq!(
+            detect_language("#!/bin/dash\necho hi\n", None, &languages),
+            Some("bash".into())
+        );
+    }
+
+    #[test]
+    fn detects_from_modeline() {
+        let languages = languages(&["lisp", "ruby"]);
+        let emacs = ";; -*- mode: emacs-lisp; coding: utf-8 -*-\n(message \"hi\")\n";
+        assert_eq!(
+            detect_language(emacs, None, &languages),
+            Some("lisp".into())
+        );
+        let vim = "puts 1\n# vim: set ft=ruby:\n";
+        assert_eq!(detect_language(vim, None, &languages), Some("ruby".into()));
+    }
+
+    #[test]
+    fn ignores_languages_which_are_not_allowed() {
+        let languages = languages(&["plaintext"]);
+        assert_eq!(
+            detect_language("#!/usr/bin/node\n", None, &languages),
+            Some("plaintext".into())
+        );
+        assert_eq!(
+            detect_language("# vim: ft=sh\n", None, &languages),
+            Some("plaintext".into())
+        );
+        assert_eq!(detect_language("#!/bin/ksh\n", None, &[]), None);
+    }
+
+    #[test]
+    fn detects_from_contents() {
+        let languages = languages(&["json", "diff", "rust", "plaintext"]);
+        assert_eq!(
+            detect_language("{\"a\": 1}", None, &languages),
+            Some("json".into())
+        );
+        let diff = "--- a/x\n+++ b/x\n@@ -1 +1 @@\n-a\n+b\n";
+        assert_eq!(detect_language(diff, None, &languages), Some("diff".into()));
+        assert_eq!(
+            detect_language("fn main() {}\n", None, &languages),
+            Some("rust".into())
+        );
+        assert_eq!(
+            detect_language("hello", None, &languages),
+            Some("plaintext".into())
+        );
+    }
+}
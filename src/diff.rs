@@ -0,0 +1,149 @@
+//! Tools for comparing pastes and their revisions.
+use crate::auth::check_access;
+use crate::config::Config;
+use crate::content::add_page_headers;
+use crate::headers::HeaderParams;
+use crate::history::ShareRef;
+use crate::models::{Revision, Share, ShareKind};
+use crate::render::{Renderer, DIFF_CONTEXT_LINES};
+use crate::storage::read_blob_to_string;
+use crate::DbConn;
+use rocket::http::{ContentType, Status};
+use rocket::response::{status, Response};
+use similar::TextDiff;
+use std::io::Cursor;
+use std::time::Duration;
+
+/// How long to spend finding the smallest diff before settling for a worse one.
+const DIFF_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Query parameters accepted when comparing pastes.
+#[derive(FromForm)]
+pub struct DiffQuery {
+    pub a: Option<String>,
+    pub b: Option<String>,
+    pub render: Option<String>,
+    pub theme: Option<String>,
+    /// The password of protected pastes, which is tried for both.
+    pub password: Option<String>,
+}
+
+/// A paste, or a revision of one, to be compared.
+struct DiffSide {
+    label: String,
+    contents: String,
+    language: String,
+}
+
+impl DiffSide {
+    fn load(
+        raw: Option<&str>,
+        password: Option<&str>,
+        headers: &HeaderParams,
+        conf: &Config,
+        conn: &DbConn,
+    ) -> Result<DiffSide, status::Custom<String>> {
+        let share_ref: ShareRef = raw
+            .ok_or_else(|| {
+                status::Custom(Status::BadRequest, "Both a and b must be given.".into())
+            })?
+            .parse()
+            .map_err(|e| status::Custom(Status::BadRequest, e))?;
+        let share = Share::get(share_ref.name, conn, &conf.upload_dir)?;
+        headers.check_read(&share, conf)?;
+        check_access(&share, password)?;
+        if share.kind != ShareKind::Paste {
+            return Err(status::Custom(
+                Status::BadRequest,
+                "Only pastes can be compared.".into(),
+            ));
+        }
+        let (label, blob, language, encryption) = match share_ref.revision {
+            Some(number) => {
+                let revision = Revision::get(&share.name, number, conn)?;
+                (
+                    format!("{}@{}", share.name, number),
+                    Some(revision.blob),
+                    revision.language,
+                    revision.encryption,
+                )
+            }
+            None => (
+                share.name.clone(),
+                share.blob,
+                share.language,
+                share.encryption,
+            ),
+        };
+        if encryption.is_some() {
+            return Err(status::Custom(
+                Status::BadRequest,
+                "Encrypted pastes can't be compared.".into(),
+            ));
+        }
+        let blob = blob.ok_or_else(|| {
+            status::Custom(
+                Status::InternalServerError,
+                "Share contents unexpectedly missing.".into(),
+            )
+        })?;
+        let contents = read_blob_to_string(&blob, conf).map_err(|_| {
+            status::Custom(Status::InternalServerError, "Could not read file.".into())
+        })?;
+        Ok(DiffSide {
+            label,
+            contents,
+            language: language.unwrap_or_default(),
+        })
+    }
+}
+
+/// Compare two pastes, giving a unified diff or a side-by-side HTML page.
+pub fn compare(
+    query: &DiffQuery,
+    headers: &HeaderParams,
+    conf: &Config,
+    renderer: &Renderer,
+    conn: &DbConn,
+) -> Result<Response<'static>, status::Custom<String>> {
+    let render_html = match query.render.as_deref() {
+        Some("html") => true,
+        Some("plain") | None => false,
+        Some(_) => {
+            return Err(status::Custom(
+                Status::BadRequest,
+                "render must be html or plain.".into(),
+            ))
+        }
+    };
+    let password = query
+        .password
+        .as_deref()
+        .or(headers.access_password.as_deref());
+    let a = DiffSide::load(query.a.as_deref(), password, headers, conf, conn)?;
+    let b = DiffSide::load(query.b.as_deref(), password, headers, conf, conn)?;
+    let diff = TextDiff::configure()
+        .timeout(DIFF_TIMEOUT)
+        .diff_lines(a.contents.as_str(), b.contents.as_str());
+    if render_html {
+        let theme = query
+            .theme
+            .as_ref()
+            .unwrap_or(&conf.rendering.default_theme);
+        let page = renderer.diff_page((&a.label, &b.label), &diff, &a.language, theme)?;
+        let mut response = Response::build();
+        response.sized_body(Cursor::new(page));
+        add_page_headers(&mut response);
+        Ok(response.finalize())
+    } else {
+        let unified = diff
+            .unified_diff()
+            .context_radius(DIFF_CONTEXT_LINES)
+            .header(&a.label, &b.label)
+            .to_string();
+        Ok(Response::build()
+            .header(ContentType::new("text", "x-diff"))
+            .sized_body(Cursor::new(unified))
+            .finalize())
+    }
+}
@@ -0,0 +1,155 @@
+//! Tools for checking and sending SHA-256 digests of share contents.
+//!
+//! Digests are exchanged in the format of RFC 9530, and also in the older
+//! `Digest` header from RFC 3230 for clients which don't support it yet.
+use rocket::http::Status;
+use rocket::response::{status, ResponseBuilder};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+
+/// The only digest algorithm supported, as named in digest headers.
+const ALGORITHM: &str = "sha-256";
+
+/// Parse a Content-Digest header, giving the SHA-256 digest as hex if present.
+///
+/// Other algorithms are ignored, as the RFC allows.
+pub fn parse_content_digest(raw: &str) -> Result<Option<String>, (Status, String)> {
+    for member in raw.split(',') {
+        let (algorithm, value) = match member.trim().split_once('=') {
+            Some(parts) => parts,
+            None => continue,
+        };
+        if !algorithm.trim().eq_ignore_ascii_case(ALGORITHM) {
+            continue;
+        }
+        let bytes = value
+            .trim()
+            .strip_prefix(':')
+            .and_then(|value| value.strip_suffix(':'))
+            .and_then(|value| base64::decode(value).ok())
+            .ok_or_else(|| {
+                (
+                    Status::BadRequest,
+                    "Content-Digest must be a base64 encoded byte sequence.".into(),
+                )
+            })?;
+        return Ok(Some(bytes.iter().map(|b| format!("{:02x}", b)).collect()));
+    }
+    Ok(None)
+}
+
+/// Hash a string, giving the digest as hex.
+pub fn hash_str(raw: &str) -> String { format!("{:x}", Sha256::digest(raw.as_bytes())) }
+
+/// Ensure that the digest given by the client, if any, matches the body.
+pub fn check_digest(expected: Option<&str>, actual: &str) -> Result<(), status::Custom<String>> {
+    match expected {
+        Some(expected) if expected != actual => Err(status::Custom(
+            Status::BadRequest,
+            "Content-Digest does not match the body received.".into(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Add headers giving the digest of the contents of a response.
+pub fn add_digest_headers(response: &mut ResponseBuilder, hash: &str) {
+    let bytes: Option<Vec<u8>> = (0..hash.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hash.get(i..i + 2)?, 16).ok())
+        .collect();
+    if let Some(bytes) = bytes {
+        let encoded = base64::encode(bytes);
+        response
+            .raw_header("Repr-Digest", format!("{}=:{}:", ALGORITHM, encoded))
+            .raw_header("Digest", format!("SHA-256={}", encoded));
+    }
+}
+
+/// Hashes everything read through it.
+pub struct DigestReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> DigestReader<R> {
+    pub fn new(inner: R) -> Self {
+        DigestReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Read the rest of the stream, and give the digest of all of it as hex.
+    pub fn finish(mut self) -> io::Result<String> {
+        io::copy(&mut self, &mut io::sink())?;
+        Ok(format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA-256 digest of "hello world".
+    const HELLO_HEX: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+    const HELLO_BASE64: &str = "uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=";
+
+    #[test]
+    fn parses_content_digest() {
+        let header = format!("sha-256=:{}:", HELLO_BASE64);
+        assert_eq!(
+            parse_content_digest(&header).unwrap().as_deref(),
+            Some(HELLO_HEX)
+        );
+        let header = format!("sha-512=:AAAA:, SHA-256 = :{}:", HELLO_BASE64);
+        assert_eq!(
+            parse_content_digest(&header).unwrap().as_deref(),
+            Some(HELLO_HEX)
+        );
+    }
+
+    #[test]
+    fn ignores_other_algorithms() {
+        assert_eq!(parse_content_digest("sha-512=:AAAA:").unwrap(), None);
+        assert_eq!(parse_content_digest("nonsense").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_content_digest() {
+        let header = format!("sha-256={}", HELLO_BASE64);
+        assert_eq!(
+            parse_content_digest(&header).unwrap_err().0,
+            Status::BadRequest
+        );
+        assert!(parse_content_digest("sha-256=:not base64!:").is_err());
+    }
+
+    #[test]
+    fn checks_digest() {
+        assert!(check_digest(None, HELLO_HEX).is_ok());
+        assert!(check_digest(Some(HELLO_HEX), HELLO_HEX).is_ok());
+        assert_eq!(
+            check_digest(Some("00"), HELLO_HEX).unwrap_err().0,
+            Status::BadRequest
+        );
+    }
+
+    #[test]
+    fn hashes_what_is_read() {
+        assert_eq!(hash_str("hello world"), HELLO_HEX);
+        let mut reader = DigestReader::new(&b"hello world"[..]);
+        let mut start = [0; 5];
+        reader.read_exact(&mut start).unwrap();
+        assert_eq!(&start, b"hello");
+        assert_eq!(reader.finish().unwrap(), HELLO_HEX);
+    }
+}
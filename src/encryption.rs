@@ -0,0 +1,247 @@
+//! Tools for encrypting share contents at rest.
+//!
+//! Encrypted files start with a header giving the ID of the key used and a
+//! random nonce. The contents follow, encrypted with XChaCha20-Poly1305 using
+//! the STREAM construction, so that large files can be handled in chunks while
+//! still being authenticated as a whole.
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+pub use chacha20poly1305::Key;
+
+/// The longest key ID allowed, since its length is stored in one byte.
+pub const MAX_KEY_ID_LENGTH: usize = 255;
+
+/// The size of each chunk of contents which is encrypted separately.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The size of the authentication tag added to each chunk.
+const TAG_SIZE: usize = 16;
+
+/// XChaCha20 nonces are 24 bytes, of which STREAM uses 5 for a counter.
+const NONCE_SIZE: usize = 19;
+
+/// Parse a base64 encoded 256 bit key.
+pub fn parse_key(raw: &str) -> Result<Key, String> {
+    let bytes = base64::decode(raw.trim()).map_err(|_| "Key is not valid base64.".to_string())?;
+    if bytes.len() != 32 {
+        return Err("Key must be 32 bytes long.".into());
+    }
+    Ok(Key::clone_from_slice(&bytes))
+}
+
+fn crypto_error(_: chacha20poly1305::aead::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Contents could not be encrypted or decrypted.",
+    )
+}
+
+/// Read up to a given number of bytes, only stopping early at the end.
+fn read_chunk(source: &mut impl Read, size: usize) -> io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(size);
+    source.by_ref().take(size as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+/// Encrypt everything from a reader into a writer, returning the writer.
+pub fn encrypt<W: Write>(
+    source: &mut impl Read,
+    mut dest: W,
+    key_id: &str,
+    key: &Key,
+) -> io::Result<W> {
+    let nonce: [u8; NONCE_SIZE] = rand::random();
+    dest.write_all(&[key_id.len() as u8])?;
+    dest.write_all(key_id.as_bytes())?;
+    dest.write_all(&nonce)?;
+    let mut encryptor = EncryptorBE32::from_aead(
+        XChaCha20Poly1305::new(key),
+        GenericArray::from_slice(&nonce),
+    );
+    // The last chunk is marked as such, so we need to know when we reach it.
+    let mut current = read_chunk(source, CHUNK_SIZE)?;
+    loop {
+        let next = read_chunk(source, CHUNK_SIZE)?;
+        if next.is_empty() {
+            let encrypted = encryptor
+                .encrypt_last(current.as_slice())
+                .map_err(crypto_error)?;
+            dest.write_all(&encrypted)?;
+            return Ok(dest);
+        }
+        let encrypted = encryptor
+            .encrypt_next(current.as_slice())
+            .map_err(crypto_error)?;
+        dest.write_all(&encrypted)?;
+        current = next;
+    }
+}
+
+/// Decrypts an encrypted file as it is read.
+pub struct DecryptReader<R> {
+    source: R,
+    /// None once the last chunk has been decrypted.
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    next_chunk: Vec<u8>,
+    plain: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// Start decrypting a file, with whichever key it was encrypted with.
+    pub fn new(mut source: R, keys: &HashMap<String, Key>) -> io::Result<Self> {
+        let mut length = [0];
+        source.read_exact(&mut length)?;
+        let mut key_id = vec![0; length[0] as usize];
+        source.read_exact(&mut key_id)?;
+        let key_id = String::from_utf8_lossy(&key_id);
+        let mut nonce = [0; NONCE_SIZE];
+        source.read_exact(&mut nonce)?;
+        let key = keys.get(key_id.as_ref()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Encryption key {} is not configured.", key_id),
+            )
+        })?;
+        let decryptor = DecryptorBE32::from_aead(
+            XChaCha20Poly1305::new(key),
+            GenericArray::from_slice(&nonce),
+        );
+        let next_chunk = read_chunk(&mut source, CHUNK_SIZE + TAG_SIZE)?;
+        Ok(DecryptReader {
+            source,
+            decryptor: Some(decryptor),
+            next_chunk,
+            plain: vec![],
+            position: 0,
+        })
+    }
+
+    fn decrypt_chunk(&mut self) -> io::Result<()> {
+        let chunk = std::mem::take(&mut self.next_chunk);
+        self.next_chunk = read_chunk(&mut self.source, CHUNK_SIZE + TAG_SIZE)?;
+        self.plain = match (self.next_chunk.is_empty(), self.decryptor.take()) {
+            (true, Some(decryptor)) => decryptor
+                .decrypt_last(chunk.as_slice())
+                .map_err(crypto_error)?,
+            (false, Some(mut decryptor)) => {
+                let plain = decryptor
+                    .decrypt_next(chunk.as_slice())
+                    .map_err(crypto_error)?;
+                self.decryptor = Some(decryptor);
+                plain
+            }
+            (_, None) => vec![],
+        };
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plain.len() {
+            if self.decryptor.is_none() {
+                return Ok(0);
+            }
+            self.decrypt_chunk()?;
+        }
+        let count = buf.len().min(self.plain.len() - self.position);
+        buf[..count].copy_from_slice(&self.plain[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_ID: &str = "2026-10";
+
+    fn keys() -> HashMap<String, Key> {
+        let mut keys = HashMap::new();
+        keys.insert(KEY_ID.to_string(), Key::clone_from_slice(&[7; 32]));
+        keys
+    }
+
+    fn encrypt_bytes(contents: &[u8]) -> Vec<u8> {
+        encrypt(&mut &contents[..], vec![], KEY_ID, &keys()[KEY_ID]).unwrap()
+    }
+
+    fn decrypt_bytes(encrypted: &[u8]) -> io::Result<Vec<u8>> {
+        let mut plain = vec![];
+        DecryptReader::new(encrypted, &keys())?.read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    /// The length of the header before the encrypted chunks.
+    fn header_length() -> usize { 1 + KEY_ID.len() + NONCE_SIZE }
+
+    #[test]
+    fn round_trips_every_chunk_boundary() {
+        for &length in &[
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            2 * CHUNK_SIZE,
+            3 * CHUNK_SIZE + 5,
+        ] {
+            let contents: Vec<u8> = (0..length).map(|i| i as u8).collect();
+            let encrypted = encrypt_bytes(&contents);
+            // Empty contents are still given one (empty) chunk.
+            let chunks = (length.max(1) + CHUNK_SIZE - 1) / CHUNK_SIZE;
+            assert_eq!(
+                encrypted.len(),
+                header_length() + length + chunks * TAG_SIZE,
+                "{}",
+                length
+            );
+            assert_eq!(decrypt_bytes(&encrypted).unwrap(), contents, "{}", length);
+        }
+    }
+
+    #[test]
+    fn uses_a_new_nonce_each_time() {
+        assert_ne!(encrypt_bytes(b"contents"), encrypt_bytes(b"contents"));
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let encrypted = encrypt_bytes(&vec![1; 2 * CHUNK_SIZE]);
+        // Dropping whole chunks is caught, since the last one is marked.
+        let first_chunk = header_length() + CHUNK_SIZE + TAG_SIZE;
+        assert!(decrypt_bytes(&encrypted[..first_chunk]).is_err());
+        assert!(decrypt_bytes(&encrypted[..encrypted.len() - 1]).is_err());
+        assert!(decrypt_bytes(&encrypted[..header_length() - 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_modified_files() {
+        let mut encrypted = encrypt_bytes(&vec![1; CHUNK_SIZE + 1]);
+        let position = header_length() + CHUNK_SIZE + TAG_SIZE;
+        encrypted[position] ^= 1;
+        assert!(decrypt_bytes(&encrypted).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let encrypted = encrypt(&mut &b"contents"[..], vec![], "old", &keys()[KEY_ID]).unwrap();
+        let error = decrypt_bytes(&encrypted).unwrap_err();
+        assert!(error.to_string().contains("old"));
+    }
+
+    #[test]
+    fn parses_keys() {
+        let key = parse_key(&format!(" {}\n", base64::encode([7; 32]))).unwrap();
+        assert_eq!(key, keys()[KEY_ID]);
+        assert!(parse_key(&base64::encode([7; 16])).is_err());
+        assert!(parse_key("not base64!").is_err());
+    }
+}
@@ -7,11 +7,19 @@ use diesel::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
 use std::path::Path;
 use std::thread;
 
-fn delete_share_files(shares: Vec<Share>, upload_path: &Path) -> Result<(), String> {
+fn clear_expired(conn: &PgConnection, upload_path: &Path) -> Result<(), String> {
+    let query = shares::expiry.lt(diesel::dsl::now);
+    let names = shares::table
+        .filter(query)
+        .select(shares::name)
+        .load::<String>(conn)
+        .map_err(|e| format!("Database error: {}", e))?;
+    // Each share is deleted separately, and only if it is still expired, since
+    // it may have been deleted or updated since it was found.
     let mut failed_deletes = vec![];
-    for share in shares {
-        if share.delete_file(upload_path).is_err() {
-            failed_deletes.push(share.name);
+    for name in names {
+        if Share::delete(&name, true, conn, upload_path).is_err() {
+            failed_deletes.push(name);
         }
     }
     if failed_deletes.is_empty() {
@@ -25,22 +33,6 @@ fn delete_share_files(shares: Vec<Share>, upload_path: &Path) -> Result<(), Stri
     }
 }
 
-fn clear_expired(conn: &PgConnection, upload_path: &Path) -> Result<(), String> {
-    let query = shares::expiry.lt(diesel::dsl::now);
-    let shares = shares::table
-        .filter(query)
-        .load::<Share>(conn)
-        .map_err(|e| format!("Database error: {}", e))?;
-    if shares.is_empty() {
-        Ok(())
-    } else {
-        diesel::delete(shares::table.filter(query))
-            .execute(conn)
-            .map_err(|e| format!("Database error: {}", e))?;
-        delete_share_files(shares, upload_path)
-    }
-}
-
 pub fn start_expiry_loop(conf: &Config) {
     let database_url = conf.make_database_url();
     let upload_path = conf.upload_dir.clone();
@@ -0,0 +1,177 @@
+//! Tools for reading multipart/form-data request bodies.
+use crate::config::Config;
+use crate::digest::DigestReader;
+use crate::storage::{BlobWriter, NewBlob};
+use multipart::server::Multipart;
+use rocket::data::Data;
+use rocket::http::Status;
+use rocket::response::status;
+use std::collections::HashMap;
+use std::io::{copy, Read};
+
+/// The name of the form field holding the contents of the share.
+pub const CONTENT_FIELD: &str = "file";
+
+/// Allowance for form fields and part headers on top of the upload size limit.
+const FORM_OVERHEAD: u64 = 64 * 1024;
+
+/// The longest value allowed for form fields other than the content field.
+const MAX_FIELD_LENGTH: u64 = 4096;
+
+/// A file uploaded as part of a form.
+pub struct FormFile {
+    pub filename: Option<String>,
+    pub mime_type: Option<String>,
+    pub blob: NewBlob,
+}
+
+/// The contents of a share, given as either a text field or a file.
+///
+/// Both are saved to temporary files, since text fields can be as large as
+/// files.
+pub enum FormContent {
+    Text(NewBlob),
+    File(FormFile),
+}
+
+impl FormContent {
+    fn blob(&self) -> &NewBlob {
+        match self {
+            FormContent::Text(blob) => blob,
+            FormContent::File(file) => &file.blob,
+        }
+    }
+
+    fn len(&self) -> u64 { self.blob().size }
+
+    /// Open the contents for reading, ensuring they are no larger than a limit.
+    pub fn open(self, limit: u64) -> Result<Box<dyn Read>, status::Custom<String>> {
+        if self.len() > limit {
+            return Err(status::Custom(
+                Status::BadRequest,
+                "Body is too large.".into(),
+            ));
+        }
+        let blob = match self {
+            FormContent::Text(blob) => blob,
+            FormContent::File(file) => file.blob,
+        };
+        Ok(Box::new(blob.open().map_err(|_| {
+            status::Custom(Status::InternalServerError, "Could not open file.".into())
+        })?))
+    }
+}
+
+/// The fields and files of a multipart form.
+pub struct FormBody {
+    pub fields: HashMap<String, String>,
+    /// Each value given for the content field, in order.
+    pub contents: Vec<FormContent>,
+    /// The SHA-256 digest of the whole form, as hex.
+    pub digest: String,
+}
+
+impl FormBody {
+    fn read_blob(data: &mut impl Read, conf: &Config) -> Result<NewBlob, (Status, String)> {
+        let mut out_stream = BlobWriter::create(conf)
+            .map_err(|_| (Status::InternalServerError, "Could not open file.".into()))?;
+        copy(data, &mut out_stream)
+            .and_then(|_| out_stream.finish())
+            .map_err(|_| (Status::BadRequest, "Could not read form data.".into()))
+    }
+
+    /// Read a form, saving the contents of the share to temporary files.
+    pub fn read(data: Data, boundary: &str, conf: &Config) -> Result<Self, (Status, String)> {
+        let limit = conf.restrictions.max_upload_size.get_bytes();
+        let mut stream = DigestReader::new(data.open().take(limit + FORM_OVERHEAD));
+        let mut multipart = Multipart::with_body(&mut stream, boundary);
+        let mut fields = HashMap::new();
+        let mut contents = vec![];
+        loop {
+            let mut entry = match multipart.read_entry() {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(_) => return Err((Status::BadRequest, "Could not read form data.".into())),
+            };
+            let name = entry.headers.name.to_string();
+            if name == CONTENT_FIELD {
+                let blob = Self::read_blob(&mut entry.data.by_ref().take(limit + 1), conf)?;
+                contents.push(match entry.headers.filename.clone() {
+                    Some(filename) => FormContent::File(FormFile {
+                        filename: Some(filename),
+                        mime_type: entry.headers.content_type.as_ref().map(|m| m.to_string()),
+                        blob,
+                    }),
+                    None => FormContent::Text(blob),
+                });
+            } else {
+                let mut value = String::new();
+                entry
+                    .data
+                    .by_ref()
+                    .take(MAX_FIELD_LENGTH + 1)
+                    .read_to_string(&mut value)
+                    .map_err(|_| (Status::BadRequest, "Could not decode form field.".into()))?;
+                if value.len() as u64 > MAX_FIELD_LENGTH {
+                    return Err((Status::BadRequest, "Form field is too long.".into()));
+                }
+                fields.insert(name, value);
+            }
+        }
+        drop(multipart);
+        let digest = stream
+            .finish()
+            .map_err(|_| (Status::BadRequest, "Could not read form data.".into()))?;
+        Ok(FormBody {
+            fields,
+            contents,
+            digest,
+        })
+    }
+
+    pub fn has_content(&self) -> bool { self.contents.iter().any(|c| c.len() > 0) }
+
+    pub fn field(&self, name: &str) -> Option<String> {
+        self.fields
+            .get(name)
+            .filter(|value| !value.is_empty())
+            .cloned()
+    }
+
+    /// The only file uploaded in the form, if there is exactly one.
+    pub fn single_file(&self) -> Option<&FormFile> {
+        match self.contents.as_slice() {
+            [FormContent::File(file)] => Some(file),
+            _ => None,
+        }
+    }
+
+    /// Take the contents of a share which is not a bundle.
+    pub fn into_content(mut self) -> Result<FormContent, status::Custom<String>> {
+        match self.contents.len() {
+            0 => Err(status::Custom(
+                Status::BadRequest,
+                "Form is missing a file field.".into(),
+            )),
+            1 => Ok(self.contents.remove(0)),
+            _ => Err(status::Custom(
+                Status::BadRequest,
+                "Only bundles can have more than one file.".into(),
+            )),
+        }
+    }
+
+    /// Take the members of a bundle.
+    pub fn into_files(self) -> Result<Vec<FormFile>, status::Custom<String>> {
+        self.contents
+            .into_iter()
+            .map(|content| match content {
+                FormContent::File(file) => Ok(file),
+                FormContent::Text(_) => Err(status::Custom(
+                    Status::BadRequest,
+                    "Bundle members must be uploaded as files.".into(),
+                )),
+            })
+            .collect()
+    }
+}
@@ -1,21 +1,87 @@
 //! Tools for parsing HTTP headers.
-use crate::auth::Auth;
+use crate::auth::{hash_access_password, Auth, ACCESS_METHOD};
+use crate::bundle::TAR_MIME_TYPE;
+use crate::compression::{parse_accept_encoding, Encoding};
 use crate::config::Config;
-use crate::models::ShareKind;
+use crate::digest::{check_digest, parse_content_digest};
+use crate::form::FormBody;
+use crate::mime::is_valid_mime_type;
+use crate::models::{RedirectCode, Share, ShareKind, Visibility};
+use crate::sniff::{is_generic, mime_types_compatible, sniff_mime_type};
 use rocket::http::Status;
 use rocket::request::{FromRequest, Outcome, Request};
 use rocket::response::status;
 use rocket::State;
+use std::convert::TryInto;
+use std::net::IpAddr;
 use std::time::{Duration, SystemTime};
 
+/// The longest Share-Encrypted header accepted.
+const MAX_ENCRYPTION_LENGTH: usize = 1024;
+
+/// Whether an Accept header prefers HTML to the raw contents of a share.
+///
+/// HTML must be named rather than matched by a wildcard, and no other type
+/// which is named may have a higher quality value.
+fn prefers_html(raw: &str) -> bool {
+    let mut html: Option<f32> = None;
+    let mut other: f32 = 0.0;
+    for item in raw.split(',') {
+        let mut parts = item.split(';');
+        let media_range = parts.next().unwrap_or("").trim();
+        let quality = parts
+            .find_map(|param| match param.split_once('=') {
+                Some((key, value)) if key.trim().eq_ignore_ascii_case("q") => {
+                    Some(value.trim().parse::<f32>().unwrap_or(1.0))
+                }
+                _ => None,
+            })
+            .unwrap_or(1.0);
+        if media_range.eq_ignore_ascii_case("text/html") {
+            html = Some(html.map_or(quality, |html| html.max(quality)));
+        } else if !media_range.is_empty() && !media_range.ends_with("/*") {
+            other = other.max(quality);
+        }
+    }
+    html.map_or(false, |html| html > 0.0 && html >= other)
+}
+
 pub struct HeaderParams {
     raw_auth: Option<String>,
+    /// The host the request was made to.
+    pub host: Option<String>,
     kind: Option<ShareKind>,
+    pub visibility: Option<Visibility>,
+    /// Whether browsers should be shown a preview page before following a link,
+    /// or Some(None) to use the server's default.
+    pub link_preview: Option<Option<bool>>,
+    /// The status code to redirect to a link with, or Some(None) to use the
+    /// server's default.
+    pub redirect_code: Option<Option<RedirectCode>>,
+    /// Only given as a form field, since it is normally part of the path.
+    pub name: Option<String>,
     pub language: Option<String>,
+    pub filename: Option<String>,
     pub mime_type: Option<String>,
     expire_after: Option<Duration>,
     pub content_length: Option<u64>,
+    /// The parameters of an end-to-end encrypted paste, normalised.
+    pub encryption: Option<String>,
+    /// The password to protect the share with, to be hashed.
+    share_password: Option<String>,
+    /// The password given to access a protected share.
+    pub access_password: Option<String>,
+    /// The SHA-256 digest of the body given by the client, as hex.
+    content_digest: Option<String>,
     pub accept_redirect: bool,
+    /// The address of the client, which is never stored.
+    pub client_ip: Option<IpAddr>,
+    pub referrer: Option<String>,
+    pub user_agent: Option<String>,
+    /// Whether the client asked for metadata to be removed from images.
+    strip_metadata: bool,
+    pub accept_html: bool,
+    pub accept_encodings: Vec<Encoding>,
 }
 
 impl HeaderParams {
@@ -24,9 +90,10 @@ impl HeaderParams {
             "link" => Ok(ShareKind::Link),
             "paste" => Ok(ShareKind::Paste),
             "file" => Ok(ShareKind::File),
+            "bundle" => Ok(ShareKind::Bundle),
             _ => Err((
                 Status::BadRequest,
-                "Share-Type must be link, paste or file.".into(),
+                "Share-Type must be link, paste, file or bundle.".into(),
             )),
         });
         match kind_or_error {
@@ -36,6 +103,93 @@ impl HeaderParams {
         }
     }
 
+    fn parse_visibility(raw: Option<&str>) -> Result<Option<Visibility>, (Status, String)> {
+        match raw {
+            Some("public") => Ok(Some(Visibility::Public)),
+            Some("unlisted") => Ok(Some(Visibility::Unlisted)),
+            Some("private") => Ok(Some(Visibility::Private)),
+            Some(_) => Err((
+                Status::BadRequest,
+                "Share-Visibility must be public, unlisted or private.".into(),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn parse_link_preview(raw: Option<&str>) -> Result<Option<Option<bool>>, (Status, String)> {
+        match raw {
+            Some("yes") => Ok(Some(Some(true))),
+            Some("no") => Ok(Some(Some(false))),
+            Some("default") => Ok(Some(None)),
+            Some(_) => Err((
+                Status::BadRequest,
+                "Share-Link-Preview must be yes, no or default.".into(),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn parse_redirect_code(
+        raw: Option<&str>,
+    ) -> Result<Option<Option<RedirectCode>>, (Status, String)> {
+        let invalid = || {
+            (
+                Status::BadRequest,
+                "Share-Redirect-Code must be 301, 302, 307, 308 or default.".to_string(),
+            )
+        };
+        match raw {
+            Some("default") => Ok(Some(None)),
+            Some(raw) => {
+                let code: i16 = raw.parse().map_err(|_| invalid())?;
+                code.try_into()
+                    .map(|code| Some(Some(code)))
+                    .map_err(|_| invalid())
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Check the parameters needed to decrypt an end-to-end encrypted paste.
+    ///
+    /// These are comma separated `name=value` pairs, such as the nonce and salt,
+    /// which must include the cipher. The server doesn't use them itself.
+    fn parse_encryption(raw: Option<&str>) -> Result<Option<String>, (Status, String)> {
+        let raw = match raw {
+            Some(raw) => raw,
+            None => return Ok(None),
+        };
+        let invalid = || {
+            (
+                Status::BadRequest,
+                "Share-Encrypted must be name=value pairs including the cipher.".to_string(),
+            )
+        };
+        if raw.len() > MAX_ENCRYPTION_LENGTH {
+            return Err(invalid());
+        }
+        let mut params = vec![];
+        for param in raw.split(',') {
+            let (name, value) = param.split_once('=').ok_or_else(invalid)?;
+            let (name, value) = (name.trim().to_ascii_lowercase(), value.trim());
+            let valid_name =
+                !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+            // Values are usually base64, which can end with = padding.
+            let valid_value = !value.is_empty()
+                && value
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+/=-_.".contains(c));
+            if !valid_name || !valid_value {
+                return Err(invalid());
+            }
+            params.push(format!("{}={}", name, value));
+        }
+        if !params.iter().any(|param| param.starts_with("cipher=")) {
+            return Err(invalid());
+        }
+        Ok(Some(params.join(", ")))
+    }
+
     fn parse_expire_after(raw: Option<&str>) -> Result<Option<Duration>, (Status, String)> {
         match raw {
             Some(as_string) => {
@@ -92,34 +246,71 @@ impl HeaderParams {
         }
     }
 
-    fn mime_type_allowed(&self, conf: &State<Config>, mime_type: &str) -> bool {
-        let mime_type = mime_type.to_string();
-        if !conf.restrictions.allowed_mime_types.is_empty() {
-            conf.restrictions.allowed_mime_types.contains(&mime_type)
+    /// Work out the MIME type of a file from the type given and the start of
+    /// its contents, if either says anything about it.
+    pub fn get_mime_type(
+        &self,
+        sample: &[u8],
+        conf: &Config,
+    ) -> Result<Option<String>, status::Custom<String>> {
+        if !self.mime_type.as_deref().map_or(true, is_valid_mime_type) {
+            return Err(status::Custom(
+                Status::BadRequest,
+                "Given Content-Type is invalid.".into(),
+            ));
+        }
+        let restrictions = &conf.restrictions;
+        let sniffed = match restrictions.sniff_mime_types || restrictions.strict_mime_types {
+            true => sniff_mime_type(sample),
+            false => None,
+        };
+        if let (true, Some(sniffed)) = (restrictions.strict_mime_types, sniffed) {
+            if !restrictions.mime_type_allowed(sniffed) {
+                return Err(status::Custom(
+                    Status::Forbidden,
+                    "File contents are of a type which is not allowed.".into(),
+                ));
+            }
+            let declared = self.mime_type.as_deref().filter(|m| !is_generic(m));
+            if !declared.map_or(true, |declared| mime_types_compatible(declared, sniffed)) {
+                return Err(status::Custom(
+                    Status::BadRequest,
+                    "Given Content-Type does not match the file contents.".into(),
+                ));
+            }
+        }
+        let mime_type = match (&self.mime_type, sniffed) {
+            (Some(mime_type), Some(sniffed))
+                if is_generic(mime_type) && restrictions.sniff_mime_types =>
+            {
+                sniffed.to_string()
+            }
+            (Some(mime_type), _) => mime_type.clone(),
+            (None, Some(sniffed)) if restrictions.sniff_mime_types => sniffed.to_string(),
+            (None, _) => return Ok(None),
+        };
+        if restrictions.mime_type_allowed(&mime_type) {
+            Ok(Some(mime_type))
         } else {
-            !conf.restrictions.disallowed_mime_types.contains(&mime_type)
+            Err(status::Custom(
+                Status::Forbidden,
+                "Given Content-Type is not allowed.".into(),
+            ))
         }
     }
 
-    pub fn get_mime_type(&self, conf: &State<Config>) -> Result<String, status::Custom<String>> {
-        match &self.mime_type {
-            Some(mime_type) => {
-                if self.mime_type_allowed(conf, mime_type) {
-                    Ok(mime_type.into())
-                } else {
-                    Err(status::Custom(
-                        Status::Forbidden,
-                        "Given Content-Type is not allowed.".into(),
-                    ))
-                }
-            }
-            None => Ok(conf.default_mime_type.clone()),
-        }
+    /// Whether the body is a tar archive, to be unpacked into a bundle.
+    pub fn is_tar(&self) -> bool {
+        self.mime_type.as_deref().map_or(false, |mime_type| {
+            mime_type.split(';').next().map(str::trim) == Some(TAR_MIME_TYPE)
+        })
     }
 
+    /// Get the share type, which is implied for tar archives.
     pub fn get_kind(&self) -> Result<ShareKind, status::Custom<String>> {
         match self.kind {
             Some(kind) => Ok(kind),
+            None if self.is_tar() => Ok(ShareKind::Bundle),
             None => Err(status::Custom(
                 Status::BadRequest,
                 "Share-Type is required.".into(),
@@ -127,6 +318,17 @@ impl HeaderParams {
         }
     }
 
+    /// Ensure that end-to-end encryption is only used for pastes.
+    pub fn check_encryption(&self, kind: ShareKind) -> Result<(), status::Custom<String>> {
+        match (&self.encryption, kind) {
+            (Some(_), kind) if kind != ShareKind::Paste => Err(status::Custom(
+                Status::BadRequest,
+                "Only pastes can be end-to-end encrypted.".into(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
     /// Ensure that the Content-Length header is lower than a given value.
     ///
     /// Note that we are not blindly trusting the header, we will still
@@ -148,6 +350,98 @@ impl HeaderParams {
         }
     }
 
+    /// Override headers with the equivalent fields of a multipart form.
+    pub fn apply_form(&mut self, form: &FormBody) -> Result<(), status::Custom<String>> {
+        let to_custom = |(status, message)| status::Custom(status, message);
+        if let Some(kind) = Self::parse_kind(form.field("type").as_deref()).map_err(to_custom)? {
+            self.kind = Some(kind);
+        }
+        let visibility = form.field("visibility");
+        if let Some(visibility) =
+            Self::parse_visibility(visibility.as_deref()).map_err(to_custom)?
+        {
+            self.visibility = Some(visibility);
+        }
+        let link_preview = form.field("link_preview");
+        if let Some(link_preview) =
+            Self::parse_link_preview(link_preview.as_deref()).map_err(to_custom)?
+        {
+            self.link_preview = Some(link_preview);
+        }
+        let redirect_code = form.field("redirect_code");
+        if let Some(redirect_code) =
+            Self::parse_redirect_code(redirect_code.as_deref()).map_err(to_custom)?
+        {
+            self.redirect_code = Some(redirect_code);
+        }
+        let expire_after = form.field("expire_after");
+        if let Some(expiry) =
+            Self::parse_expire_after(expire_after.as_deref()).map_err(to_custom)?
+        {
+            self.expire_after = Some(expiry);
+        }
+        let encryption = form.field("encrypted");
+        if let Some(encryption) =
+            Self::parse_encryption(encryption.as_deref()).map_err(to_custom)?
+        {
+            self.encryption = Some(encryption);
+        }
+        if let Some(strip_metadata) = form.field("strip_metadata") {
+            self.strip_metadata = strip_metadata == "yes";
+        }
+        self.name = form.field("name");
+        self.language = form.field("language").or_else(|| self.language.take());
+        self.filename = form.field("filename").or_else(|| self.filename.take());
+        self.share_password = form
+            .field("password")
+            .or_else(|| self.share_password.take());
+        // The Content-Type header describes the form, not the share contents.
+        self.mime_type = form.field("mime_type");
+        if let Some(file) = form.single_file() {
+            self.filename = self.filename.take().or_else(|| file.filename.clone());
+            self.mime_type = self.mime_type.take().or_else(|| file.mime_type.clone());
+        }
+        Ok(())
+    }
+
+    /// Hash the password to protect the share with, if one was given.
+    ///
+    /// An empty password means that the share should not be protected.
+    pub fn get_password_hash(&self) -> Result<Option<Option<String>>, status::Custom<String>> {
+        match self.share_password.as_deref() {
+            Some("") => Ok(Some(None)),
+            Some(password) => Ok(Some(Some(hash_access_password(password)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Ensure that the Content-Digest header, if given, matches the body.
+    pub fn check_digest(&self, actual: &str) -> Result<(), status::Custom<String>> {
+        check_digest(self.content_digest.as_deref(), actual)
+    }
+
+    /// Ensure that the client may read a share, if it is private.
+    pub fn check_read(&self, share: &Share, conf: &Config) -> Result<(), status::Custom<String>> {
+        if share.visibility != Visibility::Private {
+            return Ok(());
+        }
+        // An access password for a protected share isn't a login.
+        let no_auth = None;
+        let raw_auth = match self.access_password {
+            Some(_) => &no_auth,
+            None => &self.raw_auth,
+        };
+        Auth::from_header(raw_auth, conf)?.read_share(share)
+    }
+
+    /// Whether metadata should be removed from uploaded images.
+    pub fn strip_metadata(&self, conf: &Config) -> bool {
+        self.strip_metadata || conf.strip_metadata
+    }
+
+    /// Whether the Authorization header was set.
+    pub fn has_auth(&self) -> bool { self.raw_auth.is_some() }
+
     pub fn get_auth<'a>(
         &self,
         conf: &'a State<Config>,
@@ -162,26 +456,107 @@ impl<'a, 'r> FromRequest<'a, 'r> for HeaderParams {
     fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
         let headers = request.headers();
         let language = headers.get_one("Share-Highlighting").map(|s| s.to_string());
+        let filename = headers.get_one("Share-Filename").map(|s| s.to_string());
         let mime_type = headers.get_one("Content-Type").map(|s| s.to_string());
         let raw_auth = headers.get_one("Authorization").map(|s| s.to_string());
+        let host = headers.get_one("Host").map(|s| s.to_string());
+        let access_password = raw_auth.as_deref().and_then(|raw| {
+            let (method, content) = raw.split_once(' ')?;
+            Some(content.to_string()).filter(|_| method.eq_ignore_ascii_case(ACCESS_METHOD))
+        });
+        let share_password = headers.get_one("Share-Password").map(|s| s.to_string());
         let accept_redirect = !matches!(headers.get_one("Accept-Redirect"), Some("no"));
+        let client_ip = request.client_ip();
+        let referrer = headers.get_one("Referer").map(|s| s.to_string());
+        let user_agent = headers.get_one("User-Agent").map(|s| s.to_string());
+        let strip_metadata = matches!(headers.get_one("Strip-Metadata"), Some("yes"));
+        let accept_html = headers.get_one("Accept").map_or(false, prefers_html);
+        let accept_encodings = headers
+            .get_one("Accept-Encoding")
+            .map_or(vec![], parse_accept_encoding);
         let kind = match Self::parse_kind(headers.get_one("Share-Type")) {
             Ok(kind) => kind,
             Err(e) => return Outcome::Failure(e),
         };
+        let visibility = match Self::parse_visibility(headers.get_one("Share-Visibility")) {
+            Ok(visibility) => visibility,
+            Err(e) => return Outcome::Failure(e),
+        };
+        let link_preview = match Self::parse_link_preview(headers.get_one("Share-Link-Preview")) {
+            Ok(link_preview) => link_preview,
+            Err(e) => return Outcome::Failure(e),
+        };
+        let redirect_code = match Self::parse_redirect_code(headers.get_one("Share-Redirect-Code"))
+        {
+            Ok(redirect_code) => redirect_code,
+            Err(e) => return Outcome::Failure(e),
+        };
         let expire_after = match Self::parse_expire_after(headers.get_one("Expire-After")) {
             Ok(expires) => expires,
             Err(e) => return Outcome::Failure(e),
         };
+        let encryption = match Self::parse_encryption(headers.get_one("Share-Encrypted")) {
+            Ok(encryption) => encryption,
+            Err(e) => return Outcome::Failure(e),
+        };
         let content_length = Self::parse_content_length(headers.get_one("Content-Length"));
+        let content_digest = match headers.get_one("Content-Digest").map(parse_content_digest) {
+            Some(Ok(digest)) => digest,
+            Some(Err(e)) => return Outcome::Failure(e),
+            None => None,
+        };
         Outcome::Success(HeaderParams {
             raw_auth,
+            host,
             kind,
+            visibility,
+            link_preview,
+            redirect_code,
+            name: None,
             language,
+            filename,
             mime_type,
             expire_after,
             content_length,
+            encryption,
+            share_password,
+            access_password,
+            content_digest,
             accept_redirect,
+            client_ip,
+            referrer,
+            user_agent,
+            strip_metadata,
+            accept_html,
+            accept_encodings,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_html_from_browsers() {
+        assert!(prefers_html("text/html"));
+        assert!(prefers_html(
+            "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,*/*;q=0.8"
+        ));
+        assert!(prefers_html("TEXT/HTML; charset=utf-8; q=0.5, */*; q=0.1"));
+        assert!(prefers_html("application/json, text/html"));
+    }
+
+    #[test]
+    fn does_not_prefer_html_to_other_types() {
+        assert!(!prefers_html(""));
+        assert!(!prefers_html("*/*"));
+        assert!(!prefers_html("text/*"));
+        assert!(!prefers_html("text/plain"));
+        assert!(!prefers_html("text/html;q=0"));
+        assert!(!prefers_html("text/html;q=0.0, */*"));
+        assert!(!prefers_html("text/plain, text/html;q=0.9"));
+        assert!(!prefers_html("application/json;q=0.8, text/html;q=0.5"));
+        assert!(!prefers_html("text/html-fragment"));
+    }
+}
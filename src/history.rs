@@ -0,0 +1,158 @@
+//! Tools for keeping previous versions of paste and file shares.
+use crate::config::Config;
+use crate::models::{Revision, Share};
+use crate::schema::revisions;
+use crate::storage::{acquire_blob, release_blob};
+use crate::DbConn;
+use diesel::dsl::max;
+use diesel::{insert_into, ExpressionMethods, QueryDsl, RunQueryDsl};
+use rocket::http::{RawStr, Status};
+use rocket::request::FromParam;
+use rocket::response::status;
+use serde::Serialize;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A share name, optionally followed by "@" and a revision number, or by "+"
+/// to preview a link.
+pub struct ShareRef {
+    pub name: String,
+    pub revision: Option<i32>,
+    pub preview: bool,
+}
+
+impl FromStr for ShareRef {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        // Names can't contain "+", so this can't clash with a share.
+        if let Some(name) = raw.strip_suffix('+') {
+            return Ok(ShareRef {
+                name: name.to_string(),
+                revision: None,
+                preview: true,
+            });
+        }
+        match raw.split_once('@') {
+            Some((name, revision)) => Ok(ShareRef {
+                name: name.to_string(),
+                revision: Some(
+                    revision
+                        .parse()
+                        .map_err(|_| "Revision must be an integer.".to_string())?,
+                ),
+                preview: false,
+            }),
+            None => Ok(ShareRef {
+                name: raw.to_string(),
+                revision: None,
+                preview: false,
+            }),
+        }
+    }
+}
+
+impl<'a> FromParam<'a> for ShareRef {
+    type Error = &'a RawStr;
+
+    fn from_param(param: &'a RawStr) -> Result<Self, Self::Error> {
+        param
+            .percent_decode()
+            .map_err(|_| param)
+            .and_then(|decoded| decoded.parse().map_err(|_| param))
+    }
+}
+
+/// A revision, as listed by the API.
+#[derive(Serialize)]
+pub struct RevisionInfo {
+    pub number: i32,
+    /// When this revision was replaced, as a Unix timestamp.
+    pub replaced: u64,
+    pub language: Option<String>,
+    pub mime_type: Option<String>,
+    /// Parameters for decrypting the revision, if it is end-to-end encrypted.
+    pub encryption: Option<String>,
+}
+
+impl From<Revision> for RevisionInfo {
+    fn from(revision: Revision) -> Self {
+        RevisionInfo {
+            number: revision.number,
+            replaced: revision
+                .replaced
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            language: revision.language,
+            mime_type: revision.mime_type,
+            encryption: revision.encryption,
+        }
+    }
+}
+
+pub fn list_revisions(
+    name: &str,
+    conn: &DbConn,
+) -> Result<Vec<RevisionInfo>, status::Custom<String>> {
+    let revisions = revisions::table
+        .filter(revisions::share_name.eq(name))
+        .order(revisions::number.desc())
+        .load::<Revision>(&conn.0)
+        .map_err(|_| status::Custom(Status::InternalServerError, "Database error.".into()))?;
+    Ok(revisions.into_iter().map(RevisionInfo::from).collect())
+}
+
+/// Delete revisions which are too old to be kept.
+fn prune_revisions(
+    name: &str,
+    newest: i32,
+    conf: &Config,
+    conn: &DbConn,
+) -> Result<(), status::Custom<String>> {
+    let oldest_kept = newest - i32::from(conf.restrictions.max_revisions) + 1;
+    let pruned = diesel::delete(
+        revisions::table
+            .filter(revisions::share_name.eq(name))
+            .filter(revisions::number.lt(oldest_kept)),
+    )
+    .returning(revisions::blob)
+    .get_results::<String>(&conn.0)
+    .map_err(|_| status::Custom(Status::InternalServerError, "Database error.".into()))?;
+    for hash in pruned {
+        release_blob(&hash, &conf.upload_dir, &conn.0)?;
+    }
+    Ok(())
+}
+
+/// Keep the current contents of a share as a revision, before they are replaced.
+pub fn save_revision(
+    share: &Share,
+    conf: &Config,
+    conn: &DbConn,
+) -> Result<(), status::Custom<String>> {
+    let blob = match &share.blob {
+        Some(blob) if conf.restrictions.max_revisions > 0 => blob,
+        _ => return Ok(()),
+    };
+    let latest: Option<i32> = revisions::table
+        .filter(revisions::share_name.eq(&share.name))
+        .select(max(revisions::number))
+        .first(&conn.0)
+        .map_err(|_| status::Custom(Status::InternalServerError, "Database error.".into()))?;
+    let number = latest.unwrap_or(0) + 1;
+    acquire_blob(blob, &conn.0)?;
+    let revision = Revision {
+        share_name: share.name.clone(),
+        number,
+        replaced: SystemTime::now(),
+        language: share.language.clone(),
+        mime_type: share.mime_type.clone(),
+        blob: blob.clone(),
+        encryption: share.encryption.clone(),
+    };
+    insert_into(revisions::table)
+        .values(&revision)
+        .execute(&conn.0)
+        .map_err(|_| status::Custom(Status::InternalServerError, "Database error.".into()))?;
+    prune_revisions(&share.name, number, conf, conn)
+}
@@ -0,0 +1,346 @@
+//! Tools for checking which hosts links may point to.
+use crate::config::RestrictionsConfig;
+use rocket::http::Status;
+use rocket::response::status;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use url::{Host, Url};
+
+/// A pattern in a list of allowed or disallowed link hosts.
+///
+/// This may be an exact host like `example.com`, or a domain and all of its
+/// subdomains like `*.example.com`. Matching is case-insensitive.
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct HostPattern {
+    domain: String,
+    subdomains: bool,
+}
+
+impl HostPattern {
+    /// Whether a host, given in the form used by URLs, matches the pattern.
+    pub fn matches(&self, host: &str) -> bool {
+        let host = normalise(host);
+        if host == self.domain {
+            return true;
+        }
+        self.subdomains
+            && host
+                .strip_suffix(self.domain.as_str())
+                .map_or(false, |rest| rest.ends_with('.'))
+    }
+}
+
+impl TryFrom<String> for HostPattern {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        let (domain, subdomains) = match raw.strip_prefix("*.") {
+            Some(domain) => (domain, true),
+            None => (raw.as_str(), false),
+        };
+        let domain = normalise(domain);
+        if domain.is_empty() || domain.contains(|c: char| c == '*' || c == '/' || c == ':') {
+            return Err(format!("Invalid host pattern {:?}.", raw));
+        }
+        Ok(HostPattern { domain, subdomains })
+    }
+}
+
+/// Hosts are compared in lower case, without a trailing dot.
+fn normalise(host: &str) -> String { host.trim_end_matches('.').to_ascii_lowercase() }
+
+fn is_private_ipv4(ip: Ipv4Addr) -> bool {
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        // Carrier-grade NAT, 100.64.0.0/10.
+        || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64)
+}
+
+/// The IPv4 address embedded in an IPv6 address which reaches it, if any.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let from_segments =
+        |high: u16, low: u16| Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+    match segments {
+        // IPv4-mapped addresses, ::ffff:0:0/96.
+        [0, 0, 0, 0, 0, 0xffff, high, low] => Some(from_segments(high, low)),
+        // IPv4-compatible addresses, ::/96, apart from :: and ::1.
+        [0, 0, 0, 0, 0, 0, high, low] if high != 0 => Some(from_segments(high, low)),
+        // NAT64 addresses, 64:ff9b::/96.
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] => Some(from_segments(high, low)),
+        // 6to4 addresses, 2002::/16.
+        [0x2002, high, low, ..] => Some(from_segments(high, low)),
+        _ => None,
+    }
+}
+
+fn is_private_ipv6(ip: Ipv6Addr) -> bool {
+    if let Some(ip) = embedded_ipv4(ip) {
+        return is_private_ipv4(ip);
+    }
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        // Unique local addresses, fc00::/7.
+        || first & 0xfe00 == 0xfc00
+        // Link-local addresses, fe80::/10.
+        || first & 0xffc0 == 0xfe80
+}
+
+/// Whether a host is on the local machine or a private network.
+///
+/// Names are not resolved, so this only catches IP addresses and `localhost`.
+fn is_private_host(host: &Host<&str>) -> bool {
+    match host {
+        Host::Domain(name) => {
+            let name = normalise(name);
+            name == "localhost" || name.ends_with(".localhost")
+        }
+        Host::Ipv4(ip) => is_private_ipv4(*ip),
+        Host::Ipv6(ip) => is_private_ipv6(*ip),
+    }
+}
+
+fn host_not_allowed() -> status::Custom<String> {
+    status::Custom(
+        Status::Forbidden,
+        "Links to this host are not allowed.".into(),
+    )
+}
+
+/// Check that a link points to a host which links may be made to.
+pub fn check_link_host(
+    url: &Url,
+    restrictions: &RestrictionsConfig,
+) -> Result<(), status::Custom<String>> {
+    let host = match url.host() {
+        Some(host) => host,
+        // Links without a host, such as mailto: links, can only be allowed by
+        // their scheme.
+        None if restrictions.allowed_link_hosts.is_empty() => return Ok(()),
+        None => return Err(host_not_allowed()),
+    };
+    let is_ip = !matches!(host, Host::Domain(_));
+    if is_ip && !restrictions.allow_ip_link_hosts {
+        return Err(host_not_allowed());
+    }
+    if !restrictions.allow_private_link_hosts && is_private_host(&host) {
+        return Err(host_not_allowed());
+    }
+    let host = host.to_string();
+    let matches = |pattern: &HostPattern| pattern.matches(&host);
+    let allowed = if !restrictions.allowed_link_hosts.is_empty() {
+        restrictions.allowed_link_hosts.iter().any(matches)
+    } else {
+        !restrictions.disallowed_link_hosts.iter().any(matches)
+    };
+    if allowed {
+        Ok(())
+    } else {
+        Err(host_not_allowed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(raw: &str) -> HostPattern { HostPattern::try_from(raw.to_string()).unwrap() }
+
+    fn patterns(raw: &[&str]) -> Vec<HostPattern> { raw.iter().map(|raw| pattern(raw)).collect() }
+
+    fn check(link: &str, restrictions: &RestrictionsConfig) -> bool {
+        check_link_host(&Url::parse(link).unwrap(), restrictions).is_ok()
+    }
+
+    #[test]
+    fn matches_exact_hosts() {
+        assert!(pattern("example.com").matches("example.com"));
+        assert!(pattern("Example.COM.").matches("EXAMPLE.com"));
+        assert!(pattern("example.com").matches("example.com."));
+        assert!(!pattern("example.com").matches("www.example.com"));
+        assert!(!pattern("example.com").matches("evilexample.com"));
+        assert!(!pattern("example.com").matches("example.com.evil"));
+    }
+
+    #[test]
+    fn matches_subdomains() {
+        assert!(pattern("*.example.com").matches("example.com"));
+        assert!(pattern("*.example.com").matches("www.example.com"));
+        assert!(pattern("*.example.com").matches("a.b.EXAMPLE.com."));
+        assert!(!pattern("*.example.com").matches("evilexample.com"));
+        assert!(!pattern("*.example.com").matches("example.com.evil"));
+        assert!(!pattern("*.example.com").matches("com"));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        for raw in &[
+            "",
+            "*.",
+            ".",
+            "*",
+            "*.*.example.com",
+            "a*.example.com",
+            "example.com/x",
+        ] {
+            assert!(HostPattern::try_from(raw.to_string()).is_err(), "{}", raw);
+        }
+    }
+
+    #[test]
+    fn finds_private_ipv4_addresses() {
+        for ip in &[
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.0.1",
+            "169.254.1.1",
+        ] {
+            assert!(is_private_ipv4(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &[
+            "0.0.0.0",
+            "255.255.255.255",
+            "100.64.0.1",
+            "100.127.255.255",
+        ] {
+            assert!(is_private_ipv4(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &[
+            "203.0.113.7",
+            "8.8.8.8",
+            "100.63.255.255",
+            "100.128.0.1",
+            "172.32.0.1",
+        ] {
+            assert!(!is_private_ipv4(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn finds_private_ipv6_addresses() {
+        for ip in &["::1", "::", "fc00::1", "fd12:3456::1", "fe80::1", "febf::1"] {
+            assert!(is_private_ipv6(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &["2001:db8::1", "2606:4700::1111", "fec0::1"] {
+            assert!(!is_private_ipv6(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn finds_private_ipv4_addresses_in_ipv6() {
+        let private = [
+            "::ffff:127.0.0.1",
+            "::192.168.0.1",
+            "64:ff9b::10.0.0.1",
+            "2002:a00:1::1",
+        ];
+        for ip in &private {
+            assert!(is_private_ipv6(ip.parse().unwrap()), "{}", ip);
+        }
+        let public = [
+            "::ffff:8.8.8.8",
+            "::8.8.8.8",
+            "64:ff9b::808:808",
+            "2002:808:808::1",
+        ];
+        for ip in &public {
+            assert!(!is_private_ipv6(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn checks_private_hosts() {
+        let restrictions = RestrictionsConfig::default();
+        for link in &[
+            "http://localhost/",
+            "http://LOCALHOST./",
+            "http://app.localhost/",
+            "http://127.0.0.1:8000/",
+            "http://[::1]/",
+            "http://[::ffff:10.0.0.1]/",
+            "http://[64:ff9b::c0a8:1]/",
+            "http://[2002:c0a8:1::]/",
+        ] {
+            assert!(!check(link, &restrictions), "{}", link);
+        }
+        assert!(check("http://203.0.113.7/", &restrictions));
+        assert!(check("http://[2001:db8::1]/", &restrictions));
+
+        let restrictions = RestrictionsConfig {
+            allow_private_link_hosts: true,
+            ..RestrictionsConfig::default()
+        };
+        assert!(check("http://localhost/", &restrictions));
+        assert!(check("http://192.168.0.1/", &restrictions));
+    }
+
+    #[test]
+    fn checks_ip_hosts() {
+        let restrictions = RestrictionsConfig {
+            allow_ip_link_hosts: false,
+            ..RestrictionsConfig::default()
+        };
+        assert!(!check("http://203.0.113.7/", &restrictions));
+        assert!(!check("http://[2001:db8::1]/", &restrictions));
+        assert!(check("http://example.com/", &restrictions));
+
+        let restrictions = RestrictionsConfig {
+            allow_ip_link_hosts: false,
+            allowed_link_hosts: patterns(&["203.0.113.7"]),
+            ..RestrictionsConfig::default()
+        };
+        assert!(!check("http://203.0.113.7/", &restrictions));
+
+        let restrictions = RestrictionsConfig {
+            allowed_link_hosts: patterns(&["203.0.113.7"]),
+            ..RestrictionsConfig::default()
+        };
+        assert!(check("http://203.0.113.7/", &restrictions));
+        assert!(!check("http://203.0.113.8/", &restrictions));
+    }
+
+    #[test]
+    fn checks_disallowed_hosts() {
+        let restrictions = RestrictionsConfig {
+            disallowed_link_hosts: patterns(&["*.phishing.example", "bad.example.com"]),
+            ..RestrictionsConfig::default()
+        };
+        assert!(!check("https://phishing.example/", &restrictions));
+        assert!(!check("https://login.phishing.example/", &restrictions));
+        assert!(!check("https://BAD.example.com./", &restrictions));
+        assert!(check("https://notphishing.example/", &restrictions));
+        assert!(check("https://good.example.com/", &restrictions));
+        assert!(check("mailto:someone@phishing.example", &restrictions));
+    }
+
+    #[test]
+    fn allowed_hosts_take_precedence() {
+        let restrictions = RestrictionsConfig {
+            allowed_link_hosts: patterns(&["*.example.com"]),
+            disallowed_link_hosts: patterns(&["www.example.com"]),
+            ..RestrictionsConfig::default()
+        };
+        assert!(check("https://example.com/", &restrictions));
+        assert!(check("https://www.example.com/", &restrictions));
+        assert!(!check("https://evilexample.com/", &restrictions));
+        assert!(!check("https://example.org/", &restrictions));
+        assert!(!check("mailto:someone@example.com", &restrictions));
+    }
+
+    #[test]
+    fn allowed_hosts_do_not_allow_private_addresses() {
+        let restrictions = RestrictionsConfig {
+            allowed_link_hosts: patterns(&["localhost", "10.0.0.1"]),
+            ..RestrictionsConfig::default()
+        };
+        assert!(!check("http://localhost/", &restrictions));
+        assert!(!check("http://10.0.0.1/", &restrictions));
+    }
+}
@@ -10,52 +10,114 @@ extern crate rocket;
 extern crate rocket_contrib;
 
 mod abilities;
+mod analytics;
 mod api;
 mod auth;
 mod body;
+mod bundle;
+mod compression;
 mod config;
+mod content;
+mod detect;
+mod diff;
+mod digest;
+mod encryption;
 mod errors;
 mod expiry;
+mod form;
 mod frontend;
 mod headers;
+mod history;
+mod links;
+mod metadata;
+mod mime;
 mod models;
 mod names;
+mod render;
 mod responses;
+mod scanning;
 mod schema;
+mod sniff;
+mod storage;
+mod temp;
+mod thumbnails;
+mod view;
 
 use diesel::prelude::*;
 use diesel::PgConnection;
 use diesel_migrations::embed_migrations;
+use std::{env, process};
 
 embed_migrations!();
 
 #[database("database")]
 pub struct DbConn(rocket_contrib::databases::diesel::PgConnection);
 
-fn run_migrations(conf: &config::Config) {
+fn run_migrations(conf: &config::Config) -> PgConnection {
     let conn =
         PgConnection::establish(&conf.make_database_url()).expect("Could not connect to database");
     embedded_migrations::run(&conn).expect("Failed to run database migrations");
+    storage::migrate_legacy_files(conf, &conn).expect("Failed to move files into blob storage");
+    conn
+}
+
+/// Run a maintenance command given after the config file, instead of the server.
+fn run_command(conf: &config::Config, conn: &PgConnection, command: &str) {
+    match command {
+        "rotate-keys" => {
+            let count = storage::rotate_keys(conf, conn).unwrap_or_else(|e| {
+                eprintln!("Failed to rotate keys: {}", e);
+                process::exit(1);
+            });
+            println!("Re-encrypted {} blobs.", count);
+        }
+        _ => {
+            eprintln!("Unknown command: {}", command);
+            process::exit(1);
+        }
+    }
 }
 
 fn main() {
     let conf = config::Config::load();
-    run_migrations(&conf);
+    let conn = run_migrations(&conf);
+    if let Some(command) = env::args().nth(2) {
+        return run_command(&conf, &conn, &command);
+    }
+    // The connection is kept open until the server stops, to hold the lock.
+    if !storage::lock_for_server(&conn).expect("Could not lock database") {
+        panic!("Keys are being rotated, so the server can't start yet.");
+    }
     let frontend_path = conf.frontend_path.clone();
+    let renderer = render::Renderer::new();
+    if !renderer.has_theme(&conf.rendering.default_theme) {
+        panic!(
+            "Unknown default highlighting theme: {}",
+            conf.rendering.default_theme
+        );
+    }
     expiry::start_expiry_loop(&conf);
+    let thumbnails = thumbnails::start_thumbnail_worker(&conf);
     let mut rocket = rocket::custom(conf.configure_rocket())
         .attach(DbConn::fairing())
         .attach(errors::ErrorFairing {})
         .manage(conf)
+        .manage(renderer)
+        .manage(thumbnails)
         .mount(
             "/",
             routes![
                 api::create,
                 api::create_without_name,
                 api::get,
+                api::revisions,
+                api::thumbnail,
+                api::member,
                 api::update,
                 api::delete,
                 api::abilities,
+                api::stats,
+                api::diff,
                 api::not_found,
                 api::fallback_index,
             ],
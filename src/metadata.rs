@@ -0,0 +1,380 @@
+//! Tools for removing metadata, such as EXIF location data, from images.
+//!
+//! Metadata is removed by dropping the parts of the file which hold it, so the
+//! image data itself is never decoded or re-encoded.
+use crate::config::Config;
+use crate::sniff::{read_sniff_sample, sniff_mime_type};
+use crate::storage::{BlobWriter, NewBlob};
+use rocket::http::Status;
+use rocket::response::status;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Write};
+
+/// The image types which metadata can be removed from.
+const IMAGE_MIME_TYPES: &[&str] = &["image/png", "image/jpeg", "image/webp"];
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// PNG chunks which hold metadata: EXIF, text (which may contain XMP or
+/// anything else) and the modification time.
+const PNG_METADATA_CHUNKS: &[&[u8]] = &[b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+
+/// JPEG segments which hold metadata: APP1 (EXIF and XMP), APP13 (IPTC) and
+/// comments. Other segments, such as colour profiles, are kept.
+const JPEG_METADATA_MARKERS: &[u8] = &[0xe1, 0xed, 0xfe];
+
+const JPEG_APP1_MARKER: u8 = 0xe1;
+
+/// The EXIF tag saying which way up a photo should be shown, which is kept
+/// since it is needed to show the image correctly.
+const EXIF_ORIENTATION_TAG: u16 = 0x0112;
+
+/// WebP chunks which hold metadata.
+const WEBP_METADATA_CHUNKS: &[&[u8]] = &[b"EXIF", b"XMP "];
+
+/// Flags in the VP8X chunk of a WebP file saying that it has EXIF or XMP data.
+const WEBP_METADATA_FLAGS: u8 = 0x08 | 0x04;
+
+fn truncated() -> String { "File is truncated.".into() }
+
+fn strip_png(contents: &[u8]) -> Result<Vec<u8>, String> {
+    let mut rest = contents
+        .strip_prefix(PNG_SIGNATURE)
+        .ok_or("File is not a PNG image.")?;
+    let mut stripped = PNG_SIGNATURE.to_vec();
+    while !rest.is_empty() {
+        let header = rest.get(..8).ok_or_else(truncated)?;
+        let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        // The length doesn't include the chunk type or CRC.
+        let chunk = rest.get(..length + 12).ok_or_else(truncated)?;
+        if !PNG_METADATA_CHUNKS.contains(&&header[4..8]) {
+            stripped.extend_from_slice(chunk);
+        }
+        rest = &rest[chunk.len()..];
+    }
+    Ok(stripped)
+}
+
+/// Find the orientation in the data of an APP1 segment, if it holds EXIF.
+fn exif_orientation(data: &[u8]) -> Option<u16> {
+    let tiff = data.strip_prefix(b"Exif\0\0")?;
+    let big_endian = match tiff.get(..4)? {
+        b"MM\0\x2a" => true,
+        b"II\x2a\0" => false,
+        _ => return None,
+    };
+    let read_u16 = |at: usize| {
+        let bytes = tiff.get(at..at + 2)?.try_into().ok()?;
+        Some(match big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    };
+    let read_u32 = |at: usize| {
+        let bytes = tiff.get(at..at + 4)?.try_into().ok()?;
+        Some(match big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    };
+    // The orientation is in the first IFD, as a single SHORT.
+    let ifd = read_u32(4)? as usize;
+    let entries = read_u16(ifd)? as usize;
+    let entry = (0..entries)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| read_u16(entry) == Some(EXIF_ORIENTATION_TAG))?;
+    if read_u16(entry + 2)? != 3 || read_u32(entry + 4)? != 1 {
+        return None;
+    }
+    read_u16(entry + 8).filter(|orientation| (1..=8).contains(orientation))
+}
+
+/// An APP1 segment holding nothing but an EXIF orientation.
+fn orientation_segment(orientation: u16) -> Vec<u8> {
+    let mut segment = b"\xff\xe1\x00\x22Exif\0\0MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+    segment.extend_from_slice(&EXIF_ORIENTATION_TAG.to_be_bytes());
+    // A SHORT, with one value.
+    segment.extend_from_slice(&[0, 3, 0, 0, 0, 1]);
+    segment.extend_from_slice(&orientation.to_be_bytes());
+    // Padding for the value, then no next IFD.
+    segment.extend_from_slice(&[0; 6]);
+    segment
+}
+
+fn strip_jpeg(contents: &[u8]) -> Result<Vec<u8>, String> {
+    let mut rest = contents
+        .strip_prefix(b"\xff\xd8")
+        .ok_or("File is not a JPEG image.")?;
+    let mut stripped = b"\xff\xd8".to_vec();
+    let mut kept_orientation = false;
+    loop {
+        if rest.first() != Some(&0xff) {
+            return Err("Invalid JPEG segment.".into());
+        }
+        let marker = *rest.get(1).ok_or_else(truncated)?;
+        match marker {
+            // Padding before a marker.
+            0xff => {
+                rest = &rest[1..];
+                continue;
+            }
+            // Markers without a length.
+            0x01 | 0xd0..=0xd7 => {
+                stripped.extend_from_slice(&rest[..2]);
+                rest = &rest[2..];
+                continue;
+            }
+            // The end of the image, or the start of the compressed data which
+            // runs until the end of the image.
+            0xd9 | 0xda => {
+                stripped.extend_from_slice(rest);
+                return Ok(stripped);
+            }
+            _ => {}
+        }
+        let length = rest.get(2..4).ok_or_else(truncated)?;
+        // The length includes itself, but not the marker.
+        let length = u16::from_be_bytes(length.try_into().unwrap()) as usize;
+        if length < 2 {
+            return Err("Invalid JPEG segment.".into());
+        }
+        let segment = rest.get(..length + 2).ok_or_else(truncated)?;
+        if !JPEG_METADATA_MARKERS.contains(&marker) {
+            stripped.extend_from_slice(segment);
+        } else if marker == JPEG_APP1_MARKER && !kept_orientation {
+            if let Some(orientation) = segment.get(4..).and_then(exif_orientation) {
+                stripped.extend(orientation_segment(orientation));
+                kept_orientation = true;
+            }
+        }
+        rest = &rest[segment.len()..];
+    }
+}
+
+fn strip_webp(contents: &[u8]) -> Result<Vec<u8>, String> {
+    if contents.len() < 12 || &contents[..4] != b"RIFF" || &contents[8..12] != b"WEBP" {
+        return Err("File is not a WebP image.".into());
+    }
+    let mut rest = &contents[12..];
+    let mut chunks = vec![];
+    while !rest.is_empty() {
+        let header = rest.get(..8).ok_or_else(truncated)?;
+        let length = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        // Chunks are padded to an even length.
+        let padded = length + length % 2;
+        let chunk = rest.get(..padded + 8).ok_or_else(truncated)?;
+        match &header[..4] {
+            name if WEBP_METADATA_CHUNKS.contains(&name) => {}
+            b"VP8X" if length > 0 => {
+                let mut chunk = chunk.to_vec();
+                chunk[8] &= !WEBP_METADATA_FLAGS;
+                chunks.extend(chunk);
+            }
+            _ => chunks.extend_from_slice(chunk),
+        }
+        rest = &rest[chunk.len()..];
+    }
+    let mut stripped = b"RIFF".to_vec();
+    // The size of the RIFF container includes "WEBP", but not itself.
+    stripped.extend_from_slice(&((chunks.len() + 4) as u32).to_le_bytes());
+    stripped.extend_from_slice(b"WEBP");
+    stripped.extend(chunks);
+    Ok(stripped)
+}
+
+fn strip_image(mime_type: &str, contents: Vec<u8>) -> Result<Vec<u8>, String> {
+    match mime_type {
+        "image/png" => strip_png(&contents),
+        "image/jpeg" => strip_jpeg(&contents),
+        "image/webp" => strip_webp(&contents),
+        _ => Ok(contents),
+    }
+}
+
+/// Remove metadata from a new file, if it is an image which may have any.
+///
+/// The type of image is found from its contents rather than the type it was
+/// uploaded as, which may be wrong.
+pub fn strip_metadata(blob: NewBlob, conf: &Config) -> Result<NewBlob, status::Custom<String>> {
+    // The blob is opened by path, since opening it directly would use it up.
+    let sample = File::open(blob.path())
+        .map_err(|_| status::Custom(Status::InternalServerError, "Could not read file.".into()))
+        .and_then(read_sniff_sample)?;
+    let mime_type = match sniff_mime_type(&sample) {
+        Some(mime_type) if IMAGE_MIME_TYPES.contains(&mime_type) => mime_type,
+        _ => return Ok(blob),
+    };
+    let mut contents = vec![];
+    blob.open()
+        .and_then(|mut file| file.read_to_end(&mut contents))
+        .map_err(|_| status::Custom(Status::InternalServerError, "Could not read file.".into()))?;
+    let stripped = strip_image(mime_type, contents).map_err(|e| {
+        status::Custom(
+            Status::BadRequest,
+            format!("Could not remove metadata from image: {}", e),
+        )
+    })?;
+    BlobWriter::create(conf)
+        .and_then(|mut writer| writer.write_all(&stripped).and_then(|_| writer.finish()))
+        .map_err(|_| status::Custom(Status::InternalServerError, "Could not write file.".into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png_chunk(name: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(name);
+        chunk.extend_from_slice(data);
+        // The CRC isn't checked.
+        chunk.extend_from_slice(&[0; 4]);
+        chunk
+    }
+
+    fn jpeg_segment(marker: u8, data: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xff, marker];
+        segment.extend_from_slice(&((data.len() + 2) as u16).to_be_bytes());
+        segment.extend_from_slice(data);
+        segment
+    }
+
+    /// EXIF data in little-endian order, with a camera model and orientation.
+    fn exif(orientation: u16) -> Vec<u8> {
+        let mut exif = b"Exif\0\0II\x2a\0\x08\0\0\0\x02\0".to_vec();
+        // Model, as an ASCII string of four bytes stored in the entry.
+        exif.extend_from_slice(&[0x10, 0x01, 2, 0, 4, 0, 0, 0]);
+        exif.extend_from_slice(b"Cam\0");
+        exif.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0]);
+        exif.extend_from_slice(&orientation.to_le_bytes());
+        exif.extend_from_slice(&[0; 6]);
+        exif
+    }
+
+    fn webp_chunk(name: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = name.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn webp(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let chunks = chunks.concat();
+        let mut webp = b"RIFF".to_vec();
+        webp.extend_from_slice(&((chunks.len() + 4) as u32).to_le_bytes());
+        webp.extend_from_slice(b"WEBP");
+        webp.extend(chunks);
+        webp
+    }
+
+    #[test]
+    fn strips_png() {
+        let header = png_chunk(b"IHDR", &[0; 13]);
+        let data = png_chunk(b"IDAT", b"pixels");
+        let end = png_chunk(b"IEND", b"");
+        let png = [
+            PNG_SIGNATURE.to_vec(),
+            header.clone(),
+            png_chunk(b"tEXt", b"Author\0Me"),
+            png_chunk(b"eXIf", &exif(1)[6..]),
+            data.clone(),
+            png_chunk(b"tIME", &[0; 7]),
+            end.clone(),
+        ]
+        .concat();
+        let expected = [PNG_SIGNATURE.to_vec(), header, data, end].concat();
+        assert_eq!(strip_png(&png).unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_truncated_png() {
+        let png = [PNG_SIGNATURE.to_vec(), png_chunk(b"IHDR", &[0; 13])].concat();
+        assert!(strip_png(&png[..png.len() - 1]).is_err());
+        assert!(strip_png(b"GIF89a").is_err());
+    }
+
+    #[test]
+    fn strips_jpeg() {
+        let profile = jpeg_segment(0xe2, b"ICC_PROFILE\0");
+        let scan = b"\xff\xda\x00\x02data\xff\xd9".to_vec();
+        let jpeg = [
+            b"\xff\xd8".to_vec(),
+            jpeg_segment(0xe1, b"http://ns.adobe.com/xap/1.0/\0<x/>"),
+            profile.clone(),
+            jpeg_segment(0xed, b"Photoshop 3.0\0"),
+            jpeg_segment(0xfe, b"comment"),
+            scan.clone(),
+        ]
+        .concat();
+        let expected = [b"\xff\xd8".to_vec(), profile, scan].concat();
+        assert_eq!(strip_jpeg(&jpeg).unwrap(), expected);
+    }
+
+    #[test]
+    fn keeps_jpeg_orientation() {
+        let scan = b"\xff\xda\x00\x02data\xff\xd9".to_vec();
+        let jpeg = [
+            b"\xff\xd8".to_vec(),
+            jpeg_segment(0xe1, &exif(6)),
+            jpeg_segment(0xe1, &exif(3)),
+            scan.clone(),
+        ]
+        .concat();
+        let expected = [b"\xff\xd8".to_vec(), orientation_segment(6), scan].concat();
+        assert_eq!(strip_jpeg(&jpeg).unwrap(), expected);
+        // The new segment must be readable too.
+        assert_eq!(exif_orientation(&orientation_segment(6)[4..]), Some(6));
+        assert_eq!(exif_orientation(&exif(9)), None);
+        assert_eq!(exif_orientation(b"Exif\0\0MM\0\x2a\xff\xff\xff\xff"), None);
+    }
+
+    #[test]
+    fn rejects_truncated_jpeg() {
+        let jpeg = [b"\xff\xd8".to_vec(), jpeg_segment(0xe1, &exif(1))].concat();
+        assert!(strip_jpeg(&jpeg[..jpeg.len() - 1]).is_err());
+        assert!(strip_jpeg(b"\xff\xd8garbage").is_err());
+        // A length too short to include itself.
+        for length in &[b"\x00\x00", b"\x00\x01"] {
+            let jpeg = [&b"\xff\xd8\xff\xe1"[..], &length[..], &b"\xff\xd9"[..]].concat();
+            assert!(strip_jpeg(&jpeg).is_err());
+        }
+    }
+
+    #[test]
+    fn strips_webp() {
+        let image = webp_chunk(b"VP8 ", b"odd");
+        let webp_file = webp(&[
+            webp_chunk(b"VP8X", &[0x0c | 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            webp_chunk(b"EXIF", &exif(1)[6..]),
+            image.clone(),
+            webp_chunk(b"XMP ", b"<x/>"),
+        ]);
+        let expected = webp(&[
+            webp_chunk(b"VP8X", &[0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            image,
+        ]);
+        assert_eq!(strip_webp(&webp_file).unwrap(), expected);
+    }
+
+    #[test]
+    fn chooses_stripper_by_type() {
+        let jpeg = [
+            b"\xff\xd8".to_vec(),
+            jpeg_segment(0xfe, b"comment"),
+            b"\xff\xd9".to_vec(),
+        ]
+        .concat();
+        assert_eq!(
+            strip_image("image/jpeg", jpeg).unwrap(),
+            b"\xff\xd8\xff\xd9"
+        );
+        assert_eq!(
+            strip_image("text/plain", b"text".to_vec()).unwrap(),
+            b"text"
+        );
+    }
+}
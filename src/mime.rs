@@ -0,0 +1,172 @@
+//! Tools for parsing and matching MIME types.
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// The type and subtype of a MIME type, lower case and without parameters.
+pub fn essence(mime_type: &str) -> String {
+    mime_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Whether a string is a valid type or subtype, as defined by RFC 6838.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 127
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+}
+
+/// Whether a MIME type given by a client is a single valid type, such as
+/// `text/plain; charset=utf-8`. Parameters are not checked.
+pub fn is_valid_mime_type(mime_type: &str) -> bool {
+    let essence = essence(mime_type);
+    match essence.split_once('/') {
+        Some((top_level, subtype)) => is_valid_name(top_level) && is_valid_name(subtype),
+        None => false,
+    }
+}
+
+/// A pattern in a list of allowed or disallowed MIME types.
+///
+/// This may be an exact type like `image/png`, a whole top-level type like
+/// `image/*`, a family of types with the same suffix like `*/*+xml`, or `*/*`
+/// for any type. Parameters are ignored, and matching is case-insensitive.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct MimePattern {
+    /// The top-level type, or None for any.
+    top_level: Option<String>,
+    subtype: SubtypePattern,
+}
+
+#[derive(Clone)]
+enum SubtypePattern {
+    Any,
+    Suffix(String),
+    Exact(String),
+}
+
+impl MimePattern {
+    /// Whether a MIME type, which may have parameters, matches the pattern.
+    pub fn matches(&self, mime_type: &str) -> bool {
+        let essence = essence(mime_type);
+        let (top_level, subtype) = match essence.split_once('/') {
+            Some(parts) => parts,
+            None => return false,
+        };
+        if self.top_level.as_deref().map_or(false, |t| t != top_level) {
+            return false;
+        }
+        match &self.subtype {
+            SubtypePattern::Any => true,
+            SubtypePattern::Suffix(suffix) => {
+                subtype.rsplit_once('+').map_or(false, |(_, s)| s == suffix)
+            }
+            SubtypePattern::Exact(exact) => exact == subtype,
+        }
+    }
+}
+
+impl TryFrom<String> for MimePattern {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        let essence = essence(&raw);
+        let invalid = || format!("Invalid MIME type pattern {:?}.", raw);
+        let (top_level, subtype) = match essence.as_str() {
+            "*" => ("*", "*"),
+            essence => essence.split_once('/').ok_or_else(invalid)?,
+        };
+        let subtype = match subtype.strip_prefix('*') {
+            Some("") => SubtypePattern::Any,
+            Some(suffix) => match suffix.strip_prefix('+') {
+                Some(suffix) if is_valid_name(suffix) => SubtypePattern::Suffix(suffix.into()),
+                _ => return Err(invalid()),
+            },
+            None if is_valid_name(subtype) => SubtypePattern::Exact(subtype.into()),
+            None => return Err(invalid()),
+        };
+        let top_level = match top_level {
+            // An exact subtype only makes sense for a given top-level type.
+            "*" if matches!(subtype, SubtypePattern::Exact(_)) => return Err(invalid()),
+            "*" => None,
+            name if is_valid_name(name) => Some(name.to_string()),
+            _ => return Err(invalid()),
+        };
+        Ok(MimePattern { top_level, subtype })
+    }
+}
+
+impl fmt::Display for MimePattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/", self.top_level.as_deref().unwrap_or("*"))?;
+        match &self.subtype {
+            SubtypePattern::Any => write!(f, "*"),
+            SubtypePattern::Suffix(suffix) => write!(f, "*+{}", suffix),
+            SubtypePattern::Exact(exact) => write!(f, "{}", exact),
+        }
+    }
+}
+
+impl From<MimePattern> for String {
+    fn from(pattern: MimePattern) -> Self { pattern.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(raw: &str) -> MimePattern { MimePattern::try_from(raw.to_string()).unwrap() }
+
+    #[test]
+    fn finds_essence() {
+        assert_eq!(essence("Text/HTML; charset=utf-8"), "text/html");
+        assert_eq!(essence(" image/png "), "image/png");
+    }
+
+    #[test]
+    fn validates_mime_types() {
+        assert!(is_valid_mime_type("text/plain"));
+        assert!(is_valid_mime_type("text/plain; charset=utf-8"));
+        assert!(is_valid_mime_type("application/vnd.api+json"));
+        assert!(!is_valid_mime_type("text/plain,text/html"));
+        assert!(!is_valid_mime_type("text/html/plain"));
+        assert!(!is_valid_mime_type("text"));
+        assert!(!is_valid_mime_type("/plain"));
+        assert!(!is_valid_mime_type("text/"));
+        assert!(!is_valid_mime_type("text /plain"));
+    }
+
+    #[test]
+    fn matches_patterns() {
+        assert!(pattern("image/png").matches("IMAGE/PNG; x=y"));
+        assert!(!pattern("image/png").matches("image/jpeg"));
+        assert!(pattern("image/*").matches("image/jpeg"));
+        assert!(!pattern("image/*").matches("text/plain"));
+        assert!(pattern("*/*+xml").matches("image/svg+xml"));
+        assert!(!pattern("*/*+xml").matches("text/xml"));
+        assert!(pattern("*/*").matches("text/plain"));
+        assert!(pattern("*").matches("text/plain"));
+        assert!(!pattern("*/*").matches("nonsense"));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        for raw in &["", "image", "*/png", "image/*+", "image/x,y", "a/b/c"] {
+            assert!(MimePattern::try_from(raw.to_string()).is_err(), "{}", raw);
+        }
+    }
+
+    #[test]
+    fn displays_patterns() {
+        assert_eq!(pattern("Image/PNG").to_string(), "image/png");
+        assert_eq!(pattern("*").to_string(), "*/*");
+        assert_eq!(pattern("*/*+xml").to_string(), "*/*+xml");
+    }
+}
@@ -1,19 +1,28 @@
 use crate::config::Config;
+use crate::detect::{resolve_language, AUTO_LANGUAGE};
+use crate::history::save_revision;
+use crate::render::Renderer;
 use crate::responses::{ShareBodyResponder, ShareCreationResponder};
-use crate::schema::shares;
+use crate::schema::{bundle_members, revisions, shares};
+use crate::storage::{release_blob, store_blob, NewBlob};
+use crate::view::ViewOptions;
 use crate::DbConn;
+use chrono::NaiveDate;
 use diesel::associations::HasTable;
 use diesel::backend::Backend;
 use diesel::deserialize::{self, FromSql};
 use diesel::serialize::{self, Output, ToSql};
 use diesel::sql_types::SmallInt;
 use diesel::Identifiable;
-use diesel::{Insertable, QueryDsl, Queryable, RunQueryDsl};
+use diesel::{
+    Connection, ExpressionMethods, Insertable, OptionalExtension, PgConnection, QueryDsl,
+    Queryable, RunQueryDsl,
+};
 use rocket::http::Status;
 use rocket::response::status;
 use rocket::State;
+use serde::Deserialize;
 use std::convert::{TryFrom, TryInto};
-use std::fs::remove_file;
 use std::io;
 use std::path::Path;
 use std::time::SystemTime;
@@ -24,6 +33,19 @@ pub enum ShareKind {
     Link = 1,
     Paste = 2,
     File = 3,
+    Bundle = 4,
+}
+
+impl ShareKind {
+    /// The name of the kind, as used in the Share-Type header.
+    pub fn name(self) -> &'static str {
+        match self {
+            ShareKind::Link => "link",
+            ShareKind::Paste => "paste",
+            ShareKind::File => "file",
+            ShareKind::Bundle => "bundle",
+        }
+    }
 }
 
 impl TryFrom<i16> for ShareKind {
@@ -34,6 +56,7 @@ impl TryFrom<i16> for ShareKind {
             x if x == ShareKind::Link as i16 => Ok(ShareKind::Link),
             x if x == ShareKind::Paste as i16 => Ok(ShareKind::Paste),
             x if x == ShareKind::File as i16 => Ok(ShareKind::File),
+            x if x == ShareKind::Bundle as i16 => Ok(ShareKind::Bundle),
             _ => Err("Invalid share kind.".into()),
         }
     }
@@ -64,6 +87,134 @@ where
     }
 }
 
+/// Who may read a share.
+#[derive(Debug, Copy, Clone, AsExpression, FromSqlRow, PartialEq, Eq)]
+#[sql_type = "SmallInt"]
+pub enum Visibility {
+    /// Anyone who knows the name.
+    Public = 0,
+    /// Anyone who knows the name, but search engines are asked not to index it.
+    Unlisted = 1,
+    /// Only the creator, and passwords allowed to read private shares.
+    Private = 2,
+}
+
+impl TryFrom<i16> for Visibility {
+    type Error = String;
+
+    fn try_from(raw: i16) -> Result<Self, Self::Error> {
+        match raw {
+            x if x == Visibility::Public as i16 => Ok(Visibility::Public),
+            x if x == Visibility::Unlisted as i16 => Ok(Visibility::Unlisted),
+            x if x == Visibility::Private as i16 => Ok(Visibility::Private),
+            _ => Err("Invalid share visibility.".into()),
+        }
+    }
+}
+
+impl<DB: Backend> ToSql<SmallInt, DB> for Visibility
+where
+    i16: ToSql<SmallInt, DB>,
+{
+    fn to_sql<W>(&self, out: &mut Output<W, DB>) -> serialize::Result
+    where
+        W: io::Write,
+    {
+        (*self as i16).to_sql(out)
+    }
+}
+
+impl<DB: Backend> FromSql<SmallInt, DB> for Visibility
+where
+    i16: FromSql<SmallInt, DB>,
+{
+    fn from_sql(bytes: Option<&DB::RawValue>) -> deserialize::Result<Self> {
+        let visibility: Result<Visibility, _> = i16::from_sql(bytes)?.try_into();
+        match visibility {
+            Ok(visibility) => Ok(visibility),
+            Err(_) => Err("Invalid share visibility.".into()),
+        }
+    }
+}
+
+/// The HTTP status code used to redirect to a link, stored as the code itself.
+#[derive(Debug, Copy, Clone, AsExpression, FromSqlRow, PartialEq, Eq, Deserialize)]
+#[sql_type = "SmallInt"]
+#[serde(try_from = "i16")]
+pub enum RedirectCode {
+    MovedPermanently = 301,
+    Found = 302,
+    TemporaryRedirect = 307,
+    PermanentRedirect = 308,
+}
+
+impl RedirectCode {
+    /// Whether clients may cache the redirect forever.
+    pub fn is_permanent(self) -> bool {
+        matches!(
+            self,
+            RedirectCode::MovedPermanently | RedirectCode::PermanentRedirect
+        )
+    }
+
+    /// The temporary code which treats the request method the same way.
+    pub fn temporary(self) -> Self {
+        match self {
+            RedirectCode::MovedPermanently => RedirectCode::Found,
+            RedirectCode::PermanentRedirect => RedirectCode::TemporaryRedirect,
+            code => code,
+        }
+    }
+
+    pub fn status(self) -> Status {
+        match self {
+            RedirectCode::MovedPermanently => Status::MovedPermanently,
+            RedirectCode::Found => Status::Found,
+            RedirectCode::TemporaryRedirect => Status::TemporaryRedirect,
+            RedirectCode::PermanentRedirect => Status::PermanentRedirect,
+        }
+    }
+}
+
+impl TryFrom<i16> for RedirectCode {
+    type Error = String;
+
+    fn try_from(raw: i16) -> Result<Self, Self::Error> {
+        match raw {
+            x if x == RedirectCode::MovedPermanently as i16 => Ok(RedirectCode::MovedPermanently),
+            x if x == RedirectCode::Found as i16 => Ok(RedirectCode::Found),
+            x if x == RedirectCode::TemporaryRedirect as i16 => Ok(RedirectCode::TemporaryRedirect),
+            x if x == RedirectCode::PermanentRedirect as i16 => Ok(RedirectCode::PermanentRedirect),
+            _ => Err("Redirect code must be 301, 302, 307 or 308.".into()),
+        }
+    }
+}
+
+impl<DB: Backend> ToSql<SmallInt, DB> for RedirectCode
+where
+    i16: ToSql<SmallInt, DB>,
+{
+    fn to_sql<W>(&self, out: &mut Output<W, DB>) -> serialize::Result
+    where
+        W: io::Write,
+    {
+        (*self as i16).to_sql(out)
+    }
+}
+
+impl<DB: Backend> FromSql<SmallInt, DB> for RedirectCode
+where
+    i16: FromSql<SmallInt, DB>,
+{
+    fn from_sql(bytes: Option<&DB::RawValue>) -> deserialize::Result<Self> {
+        let code: Result<RedirectCode, _> = i16::from_sql(bytes)?.try_into();
+        match code {
+            Ok(code) => Ok(code),
+            Err(_) => Err("Invalid redirect code.".into()),
+        }
+    }
+}
+
 #[derive(Insertable, Queryable, AsChangeset)]
 pub struct Share {
     pub name: String,
@@ -73,6 +224,21 @@ pub struct Share {
     pub link: Option<String>,
     pub language: Option<String>,
     pub mime_type: Option<String>,
+    pub language_detected: bool,
+    /// Hash of the contents, for pastes and files.
+    pub blob: Option<String>,
+    /// Hash of the password needed to access the share, if any.
+    pub password: Option<String>,
+    pub visibility: Visibility,
+    /// Parameters for decrypting an end-to-end encrypted paste, which the
+    /// server stores without being able to read.
+    pub encryption: Option<String>,
+    /// Whether browsers are shown a preview page before following the link,
+    /// or None to use the server's default.
+    pub link_preview: Option<bool>,
+    /// The status code to redirect to the link with, or None to use the
+    /// server's default.
+    pub redirect_code: Option<RedirectCode>,
 }
 
 impl HasTable for Share {
@@ -100,7 +266,47 @@ impl Share {
             link: None,
             language: None,
             mime_type: None,
+            language_detected: false,
+            blob: None,
+            password: None,
+            visibility: Visibility::Public,
+            encryption: None,
+            link_preview: None,
+            redirect_code: None,
+        }
+    }
+
+    /// Check that a permanent redirect isn't used for a share which expires,
+    /// since clients would keep following it after the name is reused.
+    pub fn check_redirect_code(&self) -> Result<(), status::Custom<String>> {
+        match self.redirect_code {
+            Some(code) if code.is_permanent() && self.expiry.is_some() => Err(status::Custom(
+                Status::BadRequest,
+                "Permanent redirects can only be used for shares which don't expire.".into(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Set the highlighting language, detecting it from the paste if needed.
+    ///
+    /// Encrypted pastes only keep a language which was given, since nothing
+    /// about them should be guessed.
+    pub fn set_language(
+        &mut self,
+        requested: String,
+        sample: &str,
+        filename: Option<&str>,
+        conf: &Config,
+    ) {
+        if self.encryption.is_some() {
+            self.language = Some(requested).filter(|language| language != AUTO_LANGUAGE);
+            self.language_detected = false;
+            return;
         }
+        let (language, detected) = resolve_language(requested, sample, filename, conf);
+        self.language = Some(language);
+        self.language_detected = detected;
     }
 
     pub fn get(
@@ -118,34 +324,93 @@ impl Share {
                 _ => status::Custom(Status::InternalServerError, "Database error.".into()),
             })?;
         if share.expiry.is_some() && share.expiry < Some(SystemTime::now()) {
-            share.delete(conn, upload_path)?;
+            Share::delete(&share.name, true, &conn.0, upload_path)?;
             Err(status::Custom(Status::NotFound, "Share not found.".into()))
         } else {
             Ok(share)
         }
     }
 
-    pub fn delete_file(&self, upload_path: &Path) -> Result<(), status::Custom<String>> {
-        let mut path = upload_path.to_path_buf();
-        path.push(self.name.clone());
-        match remove_file(&path) {
-            Ok(_) => Ok(()),
-            Err(e) => match e.kind() {
-                io::ErrorKind::NotFound => Ok(()),
-                _ => Err(status::Custom(
-                    Status::InternalServerError,
-                    "Filesystem error.".into(),
-                )),
-            },
+    /// Replace the contents of a paste or file, keeping the old contents as
+    /// a revision.
+    pub fn replace_blob(
+        &mut self,
+        blob: NewBlob,
+        conf: &Config,
+        conn: &DbConn,
+    ) -> Result<(), status::Custom<String>> {
+        save_revision(self, conf, conn)?;
+        let hash = store_blob(blob, self.kind, conf, &conn.0)?;
+        if let Some(old) = self.blob.replace(hash) {
+            release_blob(&old, &conf.upload_dir, &conn.0)?;
         }
+        Ok(())
     }
 
-    pub fn delete(self, conn: &DbConn, upload_path: &Path) -> Result<(), status::Custom<String>> {
-        self.delete_file(upload_path)?;
-        diesel::delete(shares::table.find(self.name))
-            .execute(&conn.0)
+    /// Delete a share, releasing its contents and those of its revisions and
+    /// bundle members. If `expired_only` is set, it is only deleted if it has
+    /// expired.
+    ///
+    /// The same share may be deleted by several requests and the expiry loop
+    /// at once, so contents are only released by whichever of them actually
+    /// removed the row. Returns whether this call did.
+    pub fn delete(
+        name: &str,
+        expired_only: bool,
+        conn: &PgConnection,
+        upload_path: &Path,
+    ) -> Result<bool, status::Custom<String>> {
+        let hashes = conn
+            .transaction::<_, diesel::result::Error, _>(|| {
+                // Lock the share, so that revisions and members can't be added
+                // between finding their contents and deleting them.
+                let locked = shares::table
+                    .find(name)
+                    .select(shares::name)
+                    .for_update()
+                    .first::<String>(conn)
+                    .optional()?;
+                if locked.is_none() {
+                    return Ok(None);
+                }
+                // Revisions and bundle members are deleted along with the
+                // share, so their contents are found first.
+                let mut hashes = revisions::table
+                    .filter(revisions::share_name.eq(name))
+                    .select(revisions::blob)
+                    .load::<String>(conn)?;
+                hashes.extend(
+                    bundle_members::table
+                        .filter(bundle_members::share_name.eq(name))
+                        .select(bundle_members::blob)
+                        .load::<String>(conn)?,
+                );
+                let target = shares::table.filter(shares::name.eq(name));
+                let deleted = if expired_only {
+                    diesel::delete(target.filter(shares::expiry.lt(diesel::dsl::now)))
+                        .returning(shares::blob)
+                        .get_result::<Option<String>>(conn)
+                } else {
+                    diesel::delete(target)
+                        .returning(shares::blob)
+                        .get_result::<Option<String>>(conn)
+                }
+                .optional()?;
+                Ok(deleted.map(|blob| {
+                    hashes.extend(blob);
+                    hashes
+                }))
+            })
             .map_err(|_| status::Custom(Status::InternalServerError, "Database error.".into()))?;
-        Ok(())
+        match hashes {
+            Some(hashes) => {
+                for hash in hashes {
+                    release_blob(&hash, upload_path, conn)?;
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
     }
 
     pub fn creation_response(self, conf: State<Config>) -> ShareCreationResponder {
@@ -153,18 +418,96 @@ impl Share {
             conf,
             name: self.name,
             token: self.token,
+            blob: self.blob,
         }
     }
 
-    pub fn body_response(self, conf: State<Config>, accept_redirect: bool) -> ShareBodyResponder {
+    pub fn body_response<'a>(
+        self,
+        conf: State<'a, Config>,
+        renderer: State<'a, Renderer>,
+        view: ViewOptions,
+    ) -> ShareBodyResponder<'a> {
+        let link_preview = self.link_preview.unwrap_or(conf.link_preview);
+        let mut redirect_code = self.redirect_code.unwrap_or(conf.link_redirect_code);
+        // The server's default may be permanent, but it can't be used for
+        // shares which expire.
+        if self.expiry.is_some() {
+            redirect_code = redirect_code.temporary();
+        }
         ShareBodyResponder {
             conf,
-            accept_redirect,
+            renderer,
+            view,
             name: self.name,
             kind: self.kind,
+            visibility: self.visibility,
             link: self.link,
             language: self.language,
+            language_detected: self.language_detected,
             mime_type: self.mime_type,
+            blob: self.blob,
+            revision: None,
+            members: vec![],
+            archive: None,
+            locked: None,
+            encryption: self.encryption,
+            content_url: None,
+            link_preview,
+            redirect_code,
         }
     }
 }
+
+/// A previous version of the body of a paste or file share.
+#[derive(Insertable, Queryable)]
+pub struct Revision {
+    pub share_name: String,
+    pub number: i32,
+    pub replaced: SystemTime,
+    pub language: Option<String>,
+    pub mime_type: Option<String>,
+    pub blob: String,
+    pub encryption: Option<String>,
+}
+
+impl Revision {
+    pub fn get(
+        share_name: &str,
+        number: i32,
+        conn: &DbConn,
+    ) -> Result<Revision, status::Custom<String>> {
+        revisions::table
+            .find((share_name, number))
+            .first::<Revision>(&conn.0)
+            .map_err(|e| match e {
+                diesel::result::Error::NotFound => {
+                    status::Custom(Status::NotFound, "Revision not found.".into())
+                }
+                _ => status::Custom(Status::InternalServerError, "Database error.".into()),
+            })
+    }
+}
+
+/// A file which is part of a bundle share.
+#[derive(Insertable, Queryable)]
+pub struct BundleMember {
+    pub share_name: String,
+    pub position: i32,
+    pub path: String,
+    pub mime_type: String,
+    pub size: i64,
+    pub blob: String,
+}
+
+/// The number of clicks on a link on one day from one kind of source.
+#[derive(Insertable, Queryable)]
+pub struct LinkClick {
+    pub share_name: String,
+    pub day: NaiveDate,
+    /// Each of these is empty if it is unknown or not recorded.
+    pub referrer_host: String,
+    pub user_agent: String,
+    pub country: String,
+    pub clicks: i64,
+}
@@ -156,3 +156,6 @@ pub fn get_name(
 }
 
 pub fn get_token() -> String { generate_random_string(TOKEN_CHARS, 128) }
+
+/// Get a random name for a temporary file.
+pub fn get_upload_name() -> String { generate_random_string(NAME_CHARS, 32) }
@@ -0,0 +1,270 @@
+//! Tools for rendering paste contents as HTML.
+use pulldown_cmark::html::push_html;
+use pulldown_cmark::{Event, Options, Parser};
+use rocket::http::Status;
+use rocket::response::status;
+use similar::{DiffTag, TextDiff};
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Color, Theme, ThemeSet};
+use syntect::html::{
+    highlighted_html_for_string, styled_line_to_highlighted_html, IncludeBackground,
+};
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+use url::Url;
+
+const PAGE_TEMPLATE: &str = include_str!("res/page.html");
+
+/// The highlighting language of pastes which can be rendered as markdown.
+pub const MARKDOWN_LANGUAGE: &str = "markdown";
+
+/// Number of unchanged lines to show around each change in a diff.
+pub const DIFF_CONTEXT_LINES: usize = 3;
+
+/// Highlighting languages whose names syntect does not recognise, mapped to
+/// a name or file extension that it does.
+const LANGUAGE_ALIASES: &[(&str, &str)] = &[
+    ("csharp", "cs"),
+    ("objectivec", "m"),
+    ("php-template", "php"),
+    ("python-repl", "py"),
+    ("shell", "sh"),
+];
+
+pub struct Renderer {
+    syntaxes: SyntaxSet,
+    themes: ThemeSet,
+}
+
+impl Renderer {
+    pub fn new() -> Self {
+        Renderer {
+            syntaxes: SyntaxSet::load_defaults_newlines(),
+            themes: ThemeSet::load_defaults(),
+        }
+    }
+
+    pub fn theme_names(&self) -> Vec<String> { self.themes.themes.keys().cloned().collect() }
+
+    pub fn has_theme(&self, theme: &str) -> bool { self.themes.themes.contains_key(theme) }
+
+    fn get_theme(&self, theme: &str) -> Result<&Theme, status::Custom<String>> {
+        self.themes
+            .themes
+            .get(theme)
+            .ok_or_else(|| status::Custom(Status::BadRequest, "Unknown highlighting theme.".into()))
+    }
+
+    fn find_syntax(&self, language: &str) -> &SyntaxReference {
+        let token = LANGUAGE_ALIASES
+            .iter()
+            .find(|(alias, _)| *alias == language)
+            .map_or(language, |(_, token)| token);
+        self.syntaxes
+            .find_syntax_by_token(token)
+            .unwrap_or_else(|| self.syntaxes.find_syntax_plain_text())
+    }
+
+    /// Render a paste as a self-contained, syntax highlighted HTML page.
+    ///
+    /// The theme should already have been checked with `has_theme`.
+    pub fn highlight_page(
+        &self,
+        title: &str,
+        code: &str,
+        language: &str,
+        theme: &str,
+    ) -> Result<String, status::Custom<String>> {
+        let theme = self.get_theme(theme)?;
+        let body =
+            highlighted_html_for_string(code, &self.syntaxes, self.find_syntax(language), theme)
+                .map_err(|_| {
+                    status::Custom(
+                        Status::InternalServerError,
+                        "Could not highlight paste.".into(),
+                    )
+                })?;
+        let background = theme.settings.background.unwrap_or(Color::WHITE);
+        Ok(html_page(title, &body, &css_colour(background)))
+    }
+
+    /// Highlight each line separately, without trailing newlines.
+    fn highlight_lines(
+        &self,
+        lines: &[&str],
+        language: &str,
+        theme: &Theme,
+    ) -> Result<Vec<String>, status::Custom<String>> {
+        let mut highlighter = HighlightLines::new(self.find_syntax(language), theme);
+        lines
+            .iter()
+            .map(|line| {
+                let regions: Vec<_> = highlighter
+                    .highlight_line(line, &self.syntaxes)
+                    .ok()?
+                    .into_iter()
+                    .map(|(style, text)| (style, text.trim_end_matches(&['\r', '\n'][..])))
+                    .collect();
+                styled_line_to_highlighted_html(&regions, IncludeBackground::No).ok()
+            })
+            .collect::<Option<Vec<String>>>()
+            .ok_or_else(|| {
+                status::Custom(
+                    Status::InternalServerError,
+                    "Could not highlight paste.".into(),
+                )
+            })
+    }
+
+    /// Render a side-by-side, syntax highlighted diff as an HTML page.
+    pub fn diff_page(
+        &self,
+        titles: (&str, &str),
+        diff: &TextDiff<str>,
+        language: &str,
+        theme: &str,
+    ) -> Result<String, status::Custom<String>> {
+        let theme = self.get_theme(theme)?;
+        let old = self.highlight_lines(diff.old_slices(), language, theme)?;
+        let new = self.highlight_lines(diff.new_slices(), language, theme)?;
+        let mut body = format!(
+            "<table class=\"diff\">\n<tr><th colspan=\"2\">{}</th><th colspan=\"2\">{}</th></tr>\n",
+            titles.0, titles.1
+        );
+        for (index, group) in diff.grouped_ops(DIFF_CONTEXT_LINES).iter().enumerate() {
+            if index > 0 {
+                body.push_str("<tr class=\"separator\"><td colspan=\"4\">&#8943;</td></tr>\n");
+            }
+            for op in group {
+                let (tag, old_range, new_range) = op.as_tag_tuple();
+                let rows = old_range.len().max(new_range.len());
+                for row in 0..rows {
+                    let old_line = old_range.clone().nth(row);
+                    let new_line = new_range.clone().nth(row);
+                    let (old_class, new_class) = match tag {
+                        DiffTag::Equal => ("", ""),
+                        _ => ("deleted", "inserted"),
+                    };
+                    body.push_str("<tr>");
+                    push_diff_cells(&mut body, old_line, &old, old_class);
+                    push_diff_cells(&mut body, new_line, &new, new_class);
+                    body.push_str("</tr>\n");
+                }
+            }
+        }
+        body.push_str("</table>");
+        let background = theme.settings.background.unwrap_or(Color::WHITE);
+        let title = format!("{} - {}", titles.0, titles.1);
+        Ok(html_page(&title, &body, &css_colour(background)))
+    }
+}
+
+/// Add a line number and line of code to a row of a side-by-side diff.
+fn push_diff_cells(body: &mut String, line: Option<usize>, lines: &[String], class: &str) {
+    match line {
+        Some(index) => body.push_str(&format!(
+            "<td class=\"line-number\">{}</td><td class=\"{}\">{}</td>",
+            index + 1,
+            class,
+            lines[index]
+        )),
+        None => body.push_str("<td class=\"line-number\"></td><td class=\"empty\"></td>"),
+    }
+}
+
+/// Render a markdown paste as a self-contained HTML page.
+///
+/// Any raw HTML in the paste is dropped, and the output is sanitised so that
+/// links and images can't be used to run scripts.
+pub fn markdown_page(title: &str, source: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_TASKLISTS);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    let parser = Parser::new_ext(source, options).filter(|event| !matches!(event, Event::Html(_)));
+    let mut html = String::new();
+    push_html(&mut html, parser);
+    let html = ammonia::Builder::default()
+        // Allow the checkboxes generated for task lists.
+        .add_tags(&["input"])
+        .add_tag_attributes("input", &["type", "checked", "disabled"])
+        .clean(&html)
+        .to_string();
+    let body = format!("<article class=\"markdown\">\n{}</article>", html);
+    html_page(title, &body, &css_colour(Color::WHITE))
+}
+
+/// Render a page asking for the password of a protected share.
+pub fn password_page(title: &str, message: &str) -> String {
+    let body = format!(
+        "<form class=\"password\" method=\"get\">\n<p>{}</p>\n\
+         <input type=\"password\" name=\"password\" autofocus>\n\
+         <button type=\"submit\">Open</button>\n</form>",
+        ammonia::clean_text(message)
+    );
+    html_page(title, &body, &css_colour(Color::WHITE))
+}
+
+/// Render a page showing where a link goes, for the user to follow if they
+/// want to.
+///
+/// Only web links can be followed from the page, since other schemes such as
+/// `javascript:` could run in it. Anything else is just shown.
+pub fn link_preview_page(title: &str, link: &str) -> String {
+    let is_web_link =
+        Url::parse(link).map_or(false, |url| matches!(url.scheme(), "http" | "https"));
+    let link = ammonia::clean_text(link);
+    let follow = match is_web_link {
+        true => format!(
+            "<p><a href=\"{}\" rel=\"noreferrer\">Continue</a></p>\n",
+            link
+        ),
+        false => String::new(),
+    };
+    let body = format!(
+        "<div class=\"preview\">\n<p>This link goes to:</p>\n\
+         <p class=\"destination\">{}</p>\n{}</div>",
+        link, follow
+    );
+    html_page(title, &body, &css_colour(Color::WHITE))
+}
+
+fn css_colour(colour: Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", colour.r, colour.g, colour.b)
+}
+
+/// Wrap an HTML fragment in a minimal page.
+///
+/// The title is inserted as-is, so it must not contain any HTML.
+pub fn html_page(title: &str, body: &str, background: &str) -> String {
+    PAGE_TEMPLATE
+        .replace("{title}", title)
+        .replace("{background}", background)
+        .replace("{body}", body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn previews_web_links() {
+        let page = link_preview_page("link", "https://example.com/\"><script>");
+        assert!(
+            page.contains("<a href=\"https:&#47;&#47;example.com&#47;&quot;&gt;&lt;script&gt;\"")
+        );
+        assert!(!page.contains("<script>"));
+    }
+
+    #[test]
+    fn only_shows_other_links() {
+        for link in &[
+            "javascript:alert(1)",
+            "data:text/html,<script>",
+            "mailto:me@example.com",
+        ] {
+            let page = link_preview_page("link", link);
+            assert!(!page.contains("<a "), "{}", link);
+            assert!(!page.contains("<script>"), "{}", link);
+        }
+    }
+}
@@ -1,23 +1,130 @@
+use crate::bundle::{write_archive, ArchiveFormat, Manifest};
+use crate::compression::Encoding;
 use crate::config::Config;
-use crate::models::ShareKind;
+use crate::content::{add_content_host_headers, add_page_headers, add_sandbox_headers};
+use crate::digest::add_digest_headers;
+use crate::models::{BundleMember, RedirectCode, Revision, ShareKind, Visibility};
+use crate::render::{link_preview_page, markdown_page, password_page, Renderer, MARKDOWN_LANGUAGE};
+use crate::storage::{read_blob_to_string, StoredBlob};
+use crate::view::ViewOptions;
 use rocket::http::{ContentType, Status};
 use rocket::request::Request;
 use rocket::response::{Responder, Response, ResponseBuilder};
 use rocket::State;
-use std::fs::File;
+use rocket_contrib::json::Json;
 use std::io;
+use std::mem;
+
+/// Cache-Control for permanent redirects, which are cached for a day so that
+/// changes to the link reach clients before long.
+const PERMANENT_CACHE_CONTROL: &str = "max-age=86400";
+
+/// Respond with the contents of a blob.
+///
+/// Compressed blobs are sent as they are stored if the client accepts their
+/// encoding, and are decompressed on the fly otherwise. Digest headers are
+/// only sent for uncompressed responses, since they describe the contents
+/// after any content coding.
+fn stream_blob(
+    response: &mut ResponseBuilder,
+    hash: &str,
+    conf: &Config,
+    accept_encodings: &[Encoding],
+) -> Result<(), String> {
+    let stored =
+        StoredBlob::find(hash, &conf.upload_dir).map_err(|_| "Could not open file.".to_string())?;
+    response.raw_header_adjoin("Vary", "Accept-Encoding");
+    match stored.encoding {
+        Some(encoding) if accept_encodings.contains(&encoding) => {
+            let stream = stored
+                .open_encoded(conf)
+                .map_err(|_| "Could not open file.".to_string())?;
+            response
+                .raw_header("Content-Encoding", encoding.name())
+                .chunked_body(stream, 4096);
+        }
+        _ => {
+            let stream = stored
+                .open(conf)
+                .map_err(|_| "Could not open file.".to_string())?;
+            add_digest_headers(response, hash);
+            response.chunked_body(stream, 4096);
+        }
+    }
+    response.status(Status::Ok);
+    Ok(())
+}
 
 pub struct ShareBodyResponder<'a> {
     pub conf: State<'a, Config>,
-    pub accept_redirect: bool,
+    pub renderer: State<'a, Renderer>,
+    pub view: ViewOptions,
     pub name: String,
     pub kind: ShareKind,
+    pub visibility: Visibility,
     pub link: Option<String>,
     pub language: Option<String>,
+    pub language_detected: bool,
     pub mime_type: Option<String>,
+    pub blob: Option<String>,
+    pub revision: Option<i32>,
+    pub members: Vec<BundleMember>,
+    pub archive: Option<ArchiveFormat>,
+    /// Why the share can't be shown without a password, if it can't.
+    pub locked: Option<String>,
+    /// Parameters for decrypting the paste, if it is end-to-end encrypted.
+    pub encryption: Option<String>,
+    /// Where to redirect to on the user content host, if anywhere.
+    pub content_url: Option<String>,
+    /// Whether browsers are shown a preview page before following the link.
+    pub link_preview: bool,
+    /// The status code to redirect to the link with.
+    pub redirect_code: RedirectCode,
 }
 
 impl<'a> ShareBodyResponder<'a> {
+    /// Respond with a page asking for the password instead of the share.
+    pub fn lock(&mut self, message: String) { self.locked = Some(message); }
+
+    /// Whether the response will be the uploaded contents of the share, rather
+    /// than a page or manifest generated by the server.
+    pub fn serves_contents(&self) -> bool {
+        match self.kind {
+            ShareKind::File => true,
+            ShareKind::Paste => !self.view.render_html || self.encryption.is_some(),
+            ShareKind::Link | ShareKind::Bundle => false,
+        }
+    }
+
+    /// Whether the response will redirect the client to a link, rather than
+    /// show a preview of it or just give the link.
+    pub fn redirects(&self) -> bool { self.follows_link() && self.view.accept_redirect }
+
+    /// Whether the response will send the client on to a link, rather than
+    /// show a preview of it.
+    pub fn follows_link(&self) -> bool {
+        let preview = self.view.render_html && (self.link_preview || self.view.preview);
+        self.kind == ShareKind::Link && self.locked.is_none() && !preview
+    }
+
+    /// Redirect to the contents on the user content host instead.
+    pub fn redirect_to_content(&mut self, url: String) { self.content_url = Some(url); }
+
+    /// Respond with a previous version of the share instead of the current one.
+    pub fn show_revision(&mut self, revision: Revision) {
+        self.revision = Some(revision.number);
+        self.language = revision.language;
+        self.mime_type = revision.mime_type;
+        self.blob = Some(revision.blob);
+        self.encryption = revision.encryption;
+    }
+
+    /// Respond with the members of a bundle, or an archive of them.
+    pub fn show_bundle(&mut self, members: Vec<BundleMember>, archive: Option<ArchiveFormat>) {
+        self.members = members;
+        self.archive = archive;
+    }
+
     fn error_response(self, response: &mut ResponseBuilder, error: String) {
         response
             .status(Status::InternalServerError)
@@ -25,16 +132,45 @@ impl<'a> ShareBodyResponder<'a> {
             .sized_body(io::Cursor::new(error));
     }
 
+    fn password_response(self, response: &mut ResponseBuilder, message: String) {
+        response
+            .status(Status::Unauthorized)
+            .raw_header("WWW-Authenticate", "Access")
+            .sized_body(io::Cursor::new(password_page(&self.name, &message)));
+        add_page_headers(response);
+    }
+
     fn link_response(self, response: &mut ResponseBuilder) {
+        let preview = !self.follows_link();
+        response.raw_header("Vary", "Accept");
         match self.link {
+            // Only browsers are shown the preview, so API clients can still
+            // follow links.
+            Some(link) if preview => {
+                response
+                    .status(Status::Ok)
+                    .sized_body(io::Cursor::new(link_preview_page(&self.name, &link)));
+                add_page_headers(response);
+            }
+            Some(link) if self.view.accept_redirect => {
+                // Permanent redirects are only used for shares which don't
+                // expire, but the link can still be changed. They aren't
+                // cached when clicks are counted, so every click is seen.
+                let cache_control =
+                    if self.redirect_code.is_permanent() && !self.conf.analytics.count_clicks {
+                        PERMANENT_CACHE_CONTROL
+                    } else {
+                        "no-cache"
+                    };
+                response
+                    .status(self.redirect_code.status())
+                    .raw_header("Cache-Control", cache_control)
+                    .raw_header("Location", link.clone())
+                    .sized_body(io::Cursor::new(link));
+            }
             Some(link) => {
-                let status = if self.accept_redirect {
-                    Status::TemporaryRedirect
-                } else {
-                    Status::Ok
-                };
                 response
-                    .status(status)
+                    .status(Status::Ok)
                     .raw_header("Location", link.clone())
                     .sized_body(io::Cursor::new(link));
             }
@@ -42,28 +178,88 @@ impl<'a> ShareBodyResponder<'a> {
         };
     }
 
+    fn blob(&self) -> Result<&str, String> {
+        match &self.blob {
+            Some(hash) => Ok(hash),
+            None => Err("Share contents unexpectedly missing.".into()),
+        }
+    }
+
     fn stream_response(self, response: &mut ResponseBuilder) -> Result<(), ()> {
-        let mut path = self.conf.upload_dir.clone();
-        path.push(self.name.clone());
-        match File::open(path) {
-            Ok(file) => {
-                response.status(Status::Ok).chunked_body(file, 4096);
-                Ok(())
-            }
-            Err(_) => {
-                self.error_response(response, "Could not open file.".into());
+        let result = self
+            .blob()
+            .and_then(|hash| stream_blob(response, hash, &self.conf, &self.view.accept_encodings));
+        match result {
+            Ok(()) => Ok(()),
+            Err(error) => {
+                self.error_response(response, error);
                 Err(())
             }
         }
     }
 
+    fn html_response(self, response: &mut ResponseBuilder, language: String) {
+        let page = self
+            .blob()
+            .and_then(|hash| {
+                read_blob_to_string(hash, &self.conf)
+                    .map_err(|_| "Could not read file.".to_string())
+            })
+            .and_then(|code| {
+                if language == MARKDOWN_LANGUAGE && self.conf.rendering.markdown {
+                    Ok(markdown_page(&self.name, &code))
+                } else {
+                    self.renderer
+                        .highlight_page(&self.name, &code, &language, &self.view.theme)
+                        .map_err(|e| e.1)
+                }
+            });
+        match page {
+            Ok(page) => {
+                response
+                    .status(Status::Ok)
+                    .raw_header("Share-Highlighting", language)
+                    .sized_body(io::Cursor::new(page));
+                add_page_headers(response);
+            }
+            Err(error) => self.error_response(response, error),
+        }
+    }
+
+    /// Respond with the contents of an end-to-end encrypted paste as they are,
+    /// since only the client can decrypt them.
+    fn encrypted_response(self, response: &mut ResponseBuilder, params: String) {
+        let language = self.language.clone();
+        add_sandbox_headers(response, "application/octet-stream", &self.conf);
+        if self.stream_response(response).is_ok() {
+            response
+                .raw_header("Share-Encrypted", params)
+                .header(ContentType::Binary);
+            if let Some(language) = language {
+                response.raw_header("Share-Highlighting", language);
+            }
+        }
+    }
+
     fn paste_response(self, response: &mut ResponseBuilder) {
+        if let Some(params) = self.encryption.clone() {
+            return self.encrypted_response(response, params);
+        }
         match self.language.clone() {
             Some(language) => {
-                if self.stream_response(response).is_ok() {
-                    response
-                        .raw_header("Share-Highlighting", language)
-                        .header(ContentType::Plain);
+                let detected = if self.language_detected { "yes" } else { "no" };
+                response
+                    .raw_header("Vary", "Accept")
+                    .raw_header("Share-Highlighting-Detected", detected);
+                if self.view.render_html {
+                    self.html_response(response, language);
+                } else {
+                    add_sandbox_headers(response, "text/plain", &self.conf);
+                    if self.stream_response(response).is_ok() {
+                        response
+                            .raw_header("Share-Highlighting", language)
+                            .header(ContentType::Plain);
+                    }
                 }
             }
             None => self.error_response(
@@ -76,6 +272,7 @@ impl<'a> ShareBodyResponder<'a> {
     fn file_response(self, response: &mut ResponseBuilder) {
         match self.mime_type.clone() {
             Some(mime_type) => {
+                add_sandbox_headers(response, &mime_type, &self.conf);
                 if self.stream_response(response).is_ok() {
                     response.raw_header("Content-Type", mime_type);
                 }
@@ -83,17 +280,84 @@ impl<'a> ShareBodyResponder<'a> {
             None => self.error_response(response, "Mime type unexpectedly missing.".into()),
         };
     }
+
+    fn archive_response(mut self, response: &mut ResponseBuilder, format: ArchiveFormat) {
+        let members = mem::take(&mut self.members);
+        match write_archive(&self.name, members, format, &self.conf) {
+            Ok(archive) => {
+                response
+                    .status(Status::Ok)
+                    .raw_header("Content-Type", format.mime_type())
+                    .raw_header(
+                        "Content-Disposition",
+                        format!(
+                            "attachment; filename=\"{}{}\"",
+                            self.name,
+                            format.extension()
+                        ),
+                    )
+                    .chunked_body(archive, 4096);
+            }
+            Err(_) => self.error_response(response, "Could not create archive.".into()),
+        }
+    }
+
+    fn bundle_response(self, response: &mut ResponseBuilder, request: &Request) {
+        if let Some(format) = self.archive {
+            return self.archive_response(response, format);
+        }
+        response.raw_header("Vary", "Accept");
+        let manifest = Manifest::new(
+            &self.name,
+            self.members,
+            self.view.query_password.as_deref(),
+            &self.conf,
+        );
+        if self.view.render_html {
+            response
+                .status(Status::Ok)
+                .sized_body(io::Cursor::new(manifest.html_page()));
+            add_page_headers(response);
+        } else {
+            match Json(manifest).respond_to(request) {
+                Ok(json) => {
+                    response.merge(json);
+                }
+                Err(status) => {
+                    response.status(status);
+                }
+            }
+        }
+    }
 }
 
 impl<'a> Responder<'a> for ShareBodyResponder<'a> {
-    fn respond_to(self, _: &Request) -> Result<Response<'a>, Status> {
+    fn respond_to(self, request: &Request) -> Result<Response<'a>, Status> {
         let mut response = Response::build();
-        let (kind_name, _) = match self.kind {
-            ShareKind::Link => ("link", self.link_response(&mut response)),
-            ShareKind::Paste => ("paste", self.paste_response(&mut response)),
-            ShareKind::File => ("file", self.file_response(&mut response)),
+        if let Some(message) = self.locked.clone() {
+            self.password_response(&mut response, message);
+            return response.ok();
+        }
+        if let Some(url) = self.content_url.clone() {
+            response
+                .status(Status::TemporaryRedirect)
+                .raw_header("Location", url);
+            return response.ok();
+        }
+        add_content_host_headers(&mut response, request, &self.conf);
+        if let Some(number) = self.revision {
+            response.raw_header("Share-Revision", number.to_string());
+        }
+        if self.visibility != Visibility::Public {
+            response.raw_header("X-Robots-Tag", "noindex");
+        }
+        response.raw_header("Share-Type", self.kind.name());
+        match self.kind {
+            ShareKind::Link => self.link_response(&mut response),
+            ShareKind::Paste => self.paste_response(&mut response),
+            ShareKind::File => self.file_response(&mut response),
+            ShareKind::Bundle => self.bundle_response(&mut response, request),
         };
-        response.raw_header("Share-Type", kind_name);
         response.ok()
     }
 }
@@ -102,6 +366,7 @@ pub struct ShareCreationResponder<'a> {
     pub conf: State<'a, Config>,
     pub name: String,
     pub token: Option<String>,
+    pub blob: Option<String>,
 }
 
 impl<'a> Responder<'a> for ShareCreationResponder<'a> {
@@ -115,6 +380,52 @@ impl<'a> Responder<'a> for ShareCreationResponder<'a> {
         if let Some(token) = self.token {
             response.raw_header("Share-Token", token);
         };
+        if let Some(hash) = self.blob {
+            add_digest_headers(&mut response, &hash);
+        }
+        response.ok()
+    }
+}
+
+/// Responds with the contents of a blob which is not a whole share, such as a
+/// bundle member.
+pub struct BlobResponder<'a> {
+    pub conf: State<'a, Config>,
+    pub blob: String,
+    pub mime_type: String,
+    pub accept_encodings: Vec<Encoding>,
+    /// Where to redirect to on the user content host, if anywhere.
+    pub content_url: Option<String>,
+}
+
+impl<'a> Responder<'a> for BlobResponder<'a> {
+    fn respond_to(self, request: &Request) -> Result<Response<'a>, Status> {
+        let mut response = Response::build();
+        if let Some(url) = self.content_url {
+            response
+                .status(Status::TemporaryRedirect)
+                .raw_header("Location", url);
+            return response.ok();
+        }
+        add_content_host_headers(&mut response, request, &self.conf);
+        add_sandbox_headers(&mut response, &self.mime_type, &self.conf);
+        let result = stream_blob(
+            &mut response,
+            &self.blob,
+            &self.conf,
+            &self.accept_encodings,
+        );
+        match result {
+            Ok(()) => {
+                response.raw_header("Content-Type", self.mime_type);
+            }
+            Err(error) => {
+                response
+                    .status(Status::InternalServerError)
+                    .header(ContentType::Plain)
+                    .sized_body(io::Cursor::new(error));
+            }
+        }
         response.ok()
     }
 }
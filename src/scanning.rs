@@ -0,0 +1,345 @@
+//! Tools for scanning uploads for viruses before they are stored.
+use crate::config::{Config, ScannerConfig};
+use crate::storage::NewBlob;
+use rocket::http::Status;
+use rocket::response::status;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The size of each chunk of a file sent to clamd.
+const CLAMD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The result of scanning a file.
+#[derive(Debug)]
+pub enum Verdict {
+    Clean,
+    /// The file is infected, with the name of what was found.
+    Infected(String),
+}
+
+/// Something which can check files for viruses.
+pub trait Scanner {
+    fn scan(&self, path: &Path) -> io::Result<Verdict>;
+}
+
+/// Where to connect to clamd.
+pub enum ClamdAddress {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+/// Scans files by sending them to clamd with the INSTREAM command.
+pub struct ClamdScanner {
+    pub address: ClamdAddress,
+    pub timeout: Duration,
+}
+
+impl ClamdScanner {
+    fn instream(stream: &mut (impl Read + Write), path: &Path) -> io::Result<Verdict> {
+        stream.write_all(b"zINSTREAM\0")?;
+        let mut file = File::open(path)?;
+        let mut chunk = vec![0; CLAMD_CHUNK_SIZE];
+        loop {
+            let length = file.read(&mut chunk)?;
+            // Each chunk is preceded by its length, and an empty one ends it.
+            stream.write_all(&(length as u32).to_be_bytes())?;
+            if length == 0 {
+                break;
+            }
+            stream.write_all(&chunk[..length])?;
+        }
+        let mut reply = vec![];
+        stream.read_to_end(&mut reply)?;
+        let reply = String::from_utf8_lossy(&reply);
+        let reply = reply.trim_end_matches(|c| c == '\0' || c == '\n');
+        // Replies look like "stream: OK" or "stream: Eicar-Signature FOUND".
+        let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+        if result == "OK" {
+            Ok(Verdict::Clean)
+        } else if let Some(signature) = result.strip_suffix(" FOUND") {
+            Ok(Verdict::Infected(signature.to_string()))
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Unexpected reply from clamd: {}", reply),
+            ))
+        }
+    }
+}
+
+impl Scanner for ClamdScanner {
+    fn scan(&self, path: &Path) -> io::Result<Verdict> {
+        match &self.address {
+            ClamdAddress::Unix(socket) => {
+                let mut stream = UnixStream::connect(socket)?;
+                stream.set_read_timeout(Some(self.timeout))?;
+                stream.set_write_timeout(Some(self.timeout))?;
+                Self::instream(&mut stream, path)
+            }
+            ClamdAddress::Tcp(address) => {
+                let mut stream = TcpStream::connect(address)?;
+                stream.set_read_timeout(Some(self.timeout))?;
+                stream.set_write_timeout(Some(self.timeout))?;
+                Self::instream(&mut stream, path)
+            }
+        }
+    }
+}
+
+/// Scans files by running a command with the path of the file added.
+///
+/// As with clamscan, the command should exit with 0 if the file is clean or 1
+/// if it is infected, in which case the last line of its output is used as the
+/// name of what was found. It is killed if it runs for longer than the timeout.
+pub struct CommandScanner {
+    pub command: Vec<String>,
+    pub timeout: Duration,
+}
+
+/// How often to check whether a scan command has finished.
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+impl Scanner for CommandScanner {
+    fn scan(&self, path: &Path) -> io::Result<Verdict> {
+        let (program, args) = self
+            .command
+            .split_first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Scan command is empty."))?;
+        let mut child = Command::new(program)
+            .args(args)
+            .arg(path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        // The output is read separately, so that the command can't block on a
+        // full pipe while we wait for it.
+        let mut stdout = child.stdout.take().unwrap();
+        let reader = thread::spawn(move || {
+            let mut output = vec![];
+            stdout.read_to_end(&mut output).map(|_| output)
+        });
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Scan command timed out.",
+                ));
+            }
+            thread::sleep(COMMAND_POLL_INTERVAL);
+        };
+        let output = reader
+            .join()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Could not read scan output."))??;
+        match status.code() {
+            Some(0) => Ok(Verdict::Clean),
+            Some(1) => {
+                let stdout = String::from_utf8_lossy(&output);
+                let found = stdout.trim().lines().last().unwrap_or("unknown");
+                Ok(Verdict::Infected(found.to_string()))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Scan command failed: {}", status),
+            )),
+        }
+    }
+}
+
+impl ScannerConfig {
+    pub fn build(&self, timeout: Duration) -> Box<dyn Scanner> {
+        match self {
+            ScannerConfig::ClamdSocket(path) => Box::new(ClamdScanner {
+                address: ClamdAddress::Unix(path.clone()),
+                timeout,
+            }),
+            ScannerConfig::ClamdAddress(address) => Box::new(ClamdScanner {
+                address: ClamdAddress::Tcp(address.clone()),
+                timeout,
+            }),
+            ScannerConfig::Command(command) => Box::new(CommandScanner {
+                command: command.clone(),
+                timeout,
+            }),
+        }
+    }
+}
+
+/// Scan an upload before it is stored, if a scanner is configured.
+///
+/// Uploads which can't be scanned are rejected too, rather than let through.
+pub fn scan_blob(blob: &NewBlob, conf: &Config) -> Result<(), status::Custom<String>> {
+    let scanner = match &conf.scanning.scanner {
+        Some(scanner) => scanner.build(conf.scanning.timeout),
+        None => return Ok(()),
+    };
+    scan_file(&*scanner, blob.path(), &blob.hash)
+}
+
+fn scan_file(scanner: &dyn Scanner, path: &Path, hash: &str) -> Result<(), status::Custom<String>> {
+    match scanner.scan(path) {
+        Ok(Verdict::Clean) => Ok(()),
+        Ok(Verdict::Infected(found)) => {
+            eprintln!("Rejected upload {}: {} found.", hash, found);
+            Err(status::Custom(
+                Status::UnprocessableEntity,
+                "Upload was rejected by the virus scanner.".into(),
+            ))
+        }
+        Err(e) => {
+            eprintln!("Could not scan upload {}: {}", hash, e);
+            Err(status::Custom(
+                Status::InternalServerError,
+                "Could not scan upload.".into(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+
+    /// A directory for one test's files, removed when it is dropped.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("scanning-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TestDir(path)
+        }
+
+        fn file(&self, contents: &[u8]) -> PathBuf {
+            let path = self.0.join("upload");
+            fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) { let _ = fs::remove_dir_all(&self.0); }
+    }
+
+    /// Run a fake clamd which answers one INSTREAM command with a reply, and
+    /// returns what it was sent.
+    fn fake_clamd(
+        dir: &TestDir,
+        reply: &'static [u8],
+    ) -> (ClamdScanner, thread::JoinHandle<Vec<u8>>) {
+        let socket = dir.0.join("clamd.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut command = [0; 10];
+            stream.read_exact(&mut command).unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+            let mut received = vec![];
+            loop {
+                let mut length = [0; 4];
+                stream.read_exact(&mut length).unwrap();
+                let length = u32::from_be_bytes(length) as usize;
+                if length == 0 {
+                    break;
+                }
+                let mut chunk = vec![0; length];
+                stream.read_exact(&mut chunk).unwrap();
+                received.extend(chunk);
+            }
+            stream.write_all(reply).unwrap();
+            received
+        });
+        let scanner = ClamdScanner {
+            address: ClamdAddress::Unix(socket),
+            timeout: Duration::from_secs(5),
+        };
+        (scanner, server)
+    }
+
+    fn command_scanner(script: &str, timeout: Duration) -> CommandScanner {
+        CommandScanner {
+            command: vec!["sh".into(), "-c".into(), script.into(), "sh".into()],
+            timeout,
+        }
+    }
+
+    #[test]
+    fn accepts_clean_files_from_clamd() {
+        let dir = TestDir::new("clamd-clean");
+        let (scanner, server) = fake_clamd(&dir, b"stream: OK\0");
+        let contents = vec![7; CLAMD_CHUNK_SIZE + 1];
+        assert!(scan_file(&scanner, &dir.file(&contents), "hash").is_ok());
+        assert_eq!(server.join().unwrap(), contents);
+    }
+
+    #[test]
+    fn rejects_infected_files_from_clamd() {
+        let dir = TestDir::new("clamd-infected");
+        let (scanner, server) = fake_clamd(&dir, b"stream: Eicar FOUND\0");
+        let error = scan_file(&scanner, &dir.file(b"X5O!P%@AP"), "hash").unwrap_err();
+        assert_eq!(error.0, Status::UnprocessableEntity);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn fails_on_unexpected_replies_from_clamd() {
+        let dir = TestDir::new("clamd-error");
+        let (scanner, server) = fake_clamd(&dir, b"INSTREAM size limit exceeded. ERROR\0");
+        let error = scan_file(&scanner, &dir.file(b"contents"), "hash").unwrap_err();
+        assert_eq!(error.0, Status::InternalServerError);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn fails_when_clamd_is_missing() {
+        let dir = TestDir::new("clamd-missing");
+        let scanner = ClamdScanner {
+            address: ClamdAddress::Unix(dir.0.join("missing.sock")),
+            timeout: Duration::from_secs(5),
+        };
+        let error = scan_file(&scanner, &dir.file(b"contents"), "hash").unwrap_err();
+        assert_eq!(error.0, Status::InternalServerError);
+    }
+
+    #[test]
+    fn scans_with_commands() {
+        let dir = TestDir::new("command");
+        let path = dir.file(b"contents");
+        let timeout = Duration::from_secs(5);
+        let clean = command_scanner("test -f \"$1\"", timeout);
+        assert!(matches!(clean.scan(&path).unwrap(), Verdict::Clean));
+        let infected = command_scanner("echo scanning; echo \"$1: Eicar FOUND\"; exit 1", timeout);
+        match infected.scan(&path).unwrap() {
+            Verdict::Infected(found) => {
+                assert_eq!(found, format!("{}: Eicar FOUND", path.display()))
+            }
+            Verdict::Clean => panic!("File should be infected."),
+        }
+        assert!(command_scanner("exit 2", timeout).scan(&path).is_err());
+    }
+
+    #[test]
+    fn kills_commands_which_time_out() {
+        let dir = TestDir::new("command-timeout");
+        let scanner = command_scanner("sleep 10", Duration::from_millis(100));
+        let started = Instant::now();
+        let error = scanner.scan(&dir.file(b"contents")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -1,3 +1,35 @@
+table! {
+    blobs (hash) {
+        hash -> Varchar,
+        size -> Int8,
+        refs -> Int4,
+        key_id -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    bundle_members (share_name, position) {
+        share_name -> Varchar,
+        position -> Int4,
+        path -> Varchar,
+        mime_type -> Varchar,
+        size -> Int8,
+        blob -> Varchar,
+    }
+}
+
+table! {
+    revisions (share_name, number) {
+        share_name -> Varchar,
+        number -> Int4,
+        replaced -> Timestamp,
+        language -> Nullable<Varchar>,
+        mime_type -> Nullable<Varchar>,
+        blob -> Varchar,
+        encryption -> Nullable<Varchar>,
+    }
+}
+
 table! {
     shares (name) {
         name -> Varchar,
@@ -7,5 +39,29 @@ table! {
         link -> Nullable<Varchar>,
         language -> Nullable<Varchar>,
         mime_type -> Nullable<Varchar>,
+        language_detected -> Bool,
+        blob -> Nullable<Varchar>,
+        password -> Nullable<Varchar>,
+        visibility -> Int2,
+        encryption -> Nullable<Varchar>,
+        link_preview -> Nullable<Bool>,
+        redirect_code -> Nullable<Int2>,
+    }
+}
+
+table! {
+    link_clicks (share_name, day, referrer_host, user_agent, country) {
+        share_name -> Varchar,
+        day -> Date,
+        referrer_host -> Varchar,
+        user_agent -> Varchar,
+        country -> Varchar,
+        clicks -> Int8,
     }
 }
+
+joinable!(bundle_members -> shares (share_name));
+joinable!(link_clicks -> shares (share_name));
+joinable!(revisions -> shares (share_name));
+
+allow_tables_to_appear_in_same_query!(blobs, bundle_members, link_clicks, revisions, shares,);
@@ -0,0 +1,251 @@
+//! Tools for guessing the MIME type of a file from its contents.
+use crate::mime::essence;
+use rocket::http::Status;
+use rocket::response::status;
+use std::io::Read;
+
+/// How many bytes from the start of a file are used for sniffing.
+pub const SNIFF_LENGTH: usize = 512;
+
+/// The MIME type clients send when they don't know the type of a file.
+const GENERIC_MIME_TYPE: &str = "application/octet-stream";
+
+/// Magic numbers at the start of files, mapped to MIME types.
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"\x00\x00\x01\x00", "image/x-icon"),
+    (b"BM", "image/bmp"),
+    (b"II*\x00", "image/tiff"),
+    (b"MM\x00*", "image/tiff"),
+    (b"%PDF-", "application/pdf"),
+    (b"%!PS", "application/postscript"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b\x08", "application/gzip"),
+    (b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (b"BZh", "application/x-bzip2"),
+    (b"\xfd7zXZ\x00", "application/x-xz"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"Rar!\x1a\x07", "application/vnd.rar"),
+    (b"OggS\x00", "application/ogg"),
+    (b"fLaC", "audio/flac"),
+    (b"ID3", "audio/mpeg"),
+    (b"\x1a\x45\xdf\xa3", "video/webm"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+    (b"\x7fELF", "application/x-executable"),
+    (b"MZ", "application/vnd.microsoft.portable-executable"),
+];
+
+/// Formats stored in a RIFF container, by the type at bytes 8 to 12.
+const RIFF_TYPES: &[(&[u8], &str)] = &[
+    (b"WEBP", "image/webp"),
+    (b"WAVE", "audio/wav"),
+    (b"AVI ", "video/x-msvideo"),
+];
+
+/// Formats based on the ISO media file format, by the brand at bytes 8 to 12.
+const FTYP_BRANDS: &[(&[u8], &str)] = &[
+    (b"avif", "image/avif"),
+    (b"heic", "image/heic"),
+    (b"heix", "image/heic"),
+    (b"mif1", "image/heif"),
+    (b"M4A ", "audio/mp4"),
+    (b"qt  ", "video/quicktime"),
+];
+
+/// Tags which mark the start of an HTML document, from the WHATWG MIME
+/// sniffing standard.
+const HTML_TAGS: &[&str] = &[
+    "<!doctype html",
+    "<html",
+    "<head",
+    "<script",
+    "<iframe",
+    "<h1",
+    "<div",
+    "<font",
+    "<table",
+    "<a",
+    "<style",
+    "<title",
+    "<b",
+    "<body",
+    "<br",
+    "<p",
+    "<!--",
+];
+
+/// Types which browsers may run scripts in.
+const MARKUP_TYPES: &[&str] = &[
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "application/xml",
+    "text/xml",
+];
+
+/// Check for markup at the start of a file, ignoring leading whitespace.
+fn sniff_markup(sample: &[u8]) -> Option<&'static str> {
+    let sample = sample.strip_prefix(b"\xef\xbb\xbf").unwrap_or(sample);
+    let start = sample.iter().position(|b| !b.is_ascii_whitespace())?;
+    let text = String::from_utf8_lossy(&sample[start..]).to_ascii_lowercase();
+    let is_html = HTML_TAGS.iter().any(|tag| {
+        // The tag must end here, so that eg. "<aside" isn't taken as "<a".
+        text.strip_prefix(tag).map_or(false, |rest| {
+            rest.starts_with(|c: char| c == '>' || c.is_ascii_whitespace())
+        })
+    });
+    if is_html {
+        Some("text/html")
+    } else if text.starts_with("<svg") || (text.starts_with("<?xml") && text.contains("<svg")) {
+        Some("image/svg+xml")
+    } else if text.starts_with("<?xml") {
+        Some("application/xml")
+    } else {
+        None
+    }
+}
+
+/// Guess the MIME type of a file from the start of its contents.
+pub fn sniff_mime_type(sample: &[u8]) -> Option<&'static str> {
+    if let Some((_, mime_type)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| sample.starts_with(signature))
+    {
+        return Some(mime_type);
+    }
+    if sample.len() >= 12 {
+        let (container, format) = (&sample[..4], &sample[8..12]);
+        if container == b"RIFF" {
+            return RIFF_TYPES
+                .iter()
+                .find(|(riff_type, _)| *riff_type == format)
+                .map(|(_, mime_type)| *mime_type);
+        }
+        if &sample[4..8] == b"ftyp" {
+            let brand = FTYP_BRANDS.iter().find(|(brand, _)| *brand == format);
+            return Some(brand.map_or("video/mp4", |(_, mime_type)| mime_type));
+        }
+    }
+    if sample.len() >= 262 && &sample[257..262] == b"ustar" {
+        return Some("application/x-tar");
+    }
+    sniff_markup(sample)
+}
+
+/// Whether a MIME type given by a client says nothing about the file.
+pub fn is_generic(mime_type: &str) -> bool { essence(mime_type) == GENERIC_MIME_TYPE }
+
+/// Whether the type given for a file could be right, given its sniffed type.
+///
+/// This is lenient, since eg. a document may be a zip file and a JPEG may be
+/// uploaded as a PNG, but markup must not be disguised as anything else.
+pub fn mime_types_compatible(declared: &str, sniffed: &str) -> bool {
+    let declared = essence(declared);
+    if declared == sniffed {
+        return true;
+    }
+    if MARKUP_TYPES.contains(&sniffed) {
+        return MARKUP_TYPES.contains(&declared.as_str());
+    }
+    // Audio files are often uploaded with a video type, and the other way round.
+    let family = |mime_type: &str| match mime_type.split('/').next() {
+        Some("audio") | Some("video") => "media".to_string(),
+        Some(top_level) => top_level.to_string(),
+        None => String::new(),
+    };
+    sniffed.starts_with("application/") || family(&declared) == family(sniffed)
+}
+
+/// Read the start of an existing file, for sniffing its MIME type.
+pub fn read_sniff_sample(source: impl Read) -> Result<Vec<u8>, status::Custom<String>> {
+    let mut sample = Vec::with_capacity(SNIFF_LENGTH);
+    source
+        .take(SNIFF_LENGTH as u64)
+        .read_to_end(&mut sample)
+        .map_err(|_| status::Custom(Status::InternalServerError, "Could not read file.".into()))?;
+    Ok(sample)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_signatures() {
+        assert_eq!(
+            sniff_mime_type(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Some("image/png")
+        );
+        assert_eq!(sniff_mime_type(b"\xff\xd8\xff\xe0"), Some("image/jpeg"));
+        assert_eq!(sniff_mime_type(b"%PDF-1.7\n"), Some("application/pdf"));
+        assert_eq!(sniff_mime_type(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff_mime_type(b"RIFF\0\0\0\0????"), None);
+        assert_eq!(sniff_mime_type(b"\0\0\0\x1cftypheic"), Some("image/heic"));
+        assert_eq!(sniff_mime_type(b"\0\0\0\x1cftypisom"), Some("video/mp4"));
+        let mut tar = vec![0; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(sniff_mime_type(&tar), Some("application/x-tar"));
+        assert_eq!(sniff_mime_type(b"just some text"), None);
+        assert_eq!(sniff_mime_type(b""), None);
+    }
+
+    #[test]
+    fn sniffs_markup() {
+        assert_eq!(sniff_mime_type(b"<!DOCTYPE html><p>hi"), Some("text/html"));
+        assert_eq!(
+            sniff_mime_type(b"\xef\xbb\xbf \n\t<SCRIPT>alert(1)"),
+            Some("text/html")
+        );
+        assert_eq!(sniff_mime_type(b"<a href=x>"), Some("text/html"));
+        assert_eq!(sniff_mime_type(b"<aside>"), None);
+        assert_eq!(
+            sniff_mime_type(b"<svg xmlns=\"http://www.w3.org/2000/svg\">"),
+            Some("image/svg+xml")
+        );
+        assert_eq!(
+            sniff_mime_type(b"<?xml version=\"1.0\"?>\n<svg>"),
+            Some("image/svg+xml")
+        );
+        assert_eq!(
+            sniff_mime_type(b"<?xml version=\"1.0\"?>\n<feed>"),
+            Some("application/xml")
+        );
+    }
+
+    #[test]
+    fn finds_generic_types() {
+        assert!(is_generic("application/octet-stream"));
+        assert!(is_generic("Application/Octet-Stream; charset=binary"));
+        assert!(!is_generic("text/plain"));
+    }
+
+    #[test]
+    fn checks_compatible_types() {
+        assert!(mime_types_compatible("image/png", "image/png"));
+        assert!(mime_types_compatible("image/png", "image/jpeg"));
+        assert!(mime_types_compatible("video/mp4", "audio/mp4"));
+        assert!(mime_types_compatible(
+            "application/vnd.oasis.opendocument.text",
+            "application/zip"
+        ));
+        assert!(!mime_types_compatible("text/plain", "image/png"));
+        // Markup can't be disguised as anything else.
+        assert!(!mime_types_compatible("text/plain", "text/html"));
+        assert!(!mime_types_compatible("image/png", "image/svg+xml"));
+        assert!(mime_types_compatible("application/xhtml+xml", "text/html"));
+    }
+
+    #[test]
+    fn reads_sniff_samples() {
+        let contents = vec![1; SNIFF_LENGTH * 2];
+        assert_eq!(
+            read_sniff_sample(&contents[..]).unwrap().len(),
+            SNIFF_LENGTH
+        );
+        assert_eq!(read_sniff_sample(&b"short"[..]).unwrap(), b"short");
+    }
+}
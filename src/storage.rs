@@ -0,0 +1,626 @@
+//! Tools for storing share contents as deduplicated blobs.
+//!
+//! Contents are stored once for each distinct SHA-256 hash, and shares,
+//! revisions and bundle members refer to them by hash. Each blob keeps a count
+//! of the references to it, and is deleted once nothing uses it.
+//!
+//! Blobs may be stored compressed, in which case the file name has the
+//! extension of the encoding, and then encrypted, in which case ".enc" is
+//! added. The hash is always of the original contents.
+//!
+//! Thumbnails of images are kept next to the blob they were made from, and
+//! deleted along with it.
+use crate::compression::{Encoding, ENCODINGS};
+use crate::config::Config;
+use crate::encryption::{encrypt, DecryptReader, Key};
+use crate::models::ShareKind;
+use crate::schema::{blobs, bundle_members, revisions, shares};
+use crate::temp::{TempFile, TempFileReader};
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Bool, Integer};
+use diesel::{
+    insert_into, ExpressionMethods, PgConnection, PgExpressionMethods, QueryDsl, QueryResult,
+    RunQueryDsl,
+};
+use rocket::http::Status;
+use rocket::response::status;
+use sha2::{Digest, Sha256};
+use std::fs::{create_dir_all, read_dir, remove_file, File};
+use std::io::{self, copy, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Directory within the upload directory where blobs are kept.
+///
+/// Share names cannot contain "@", so this can't clash with a share.
+const BLOBS_DIR: &str = "@blobs";
+
+/// Directories where revisions and bundle members were kept before blobs.
+const LEGACY_REVISIONS_DIR: &str = "@revisions";
+const LEGACY_BUNDLES_DIR: &str = "@bundles";
+
+/// Key of the Postgres advisory lock which running servers hold shared, and
+/// which commands changing blobs behind their backs take exclusively.
+const SERVER_LOCK_KEY: i64 = 0x7368_6172_6573;
+
+sql_function!(fn pg_try_advisory_lock(key: BigInt) -> Bool);
+sql_function!(fn pg_try_advisory_lock_shared(key: BigInt) -> Bool);
+
+/// The first key of the Postgres advisory locks on blobs, which are taken
+/// with two keys so that they can't clash with the server lock.
+const BLOB_LOCK_CLASS: i32 = 0x626c_6f62;
+
+/// A lock on a blob, held while storing it or changing its references, so that
+/// it can't be deleted between one request finding it and adding a reference to
+/// it. This is a database lock, so that it works across servers.
+///
+/// The lock is released when this is dropped.
+struct BlobLock<'a> {
+    conn: &'a PgConnection,
+    key: i32,
+}
+
+impl<'a> BlobLock<'a> {
+    fn take(hash: &str, conn: &'a PgConnection) -> QueryResult<Self> {
+        // Locks are keyed by the start of the hash, which is random enough.
+        let key = hash
+            .get(..8)
+            .and_then(|start| u32::from_str_radix(start, 16).ok())
+            .unwrap_or_default() as i32;
+        sql_query("SELECT pg_advisory_lock($1, $2)")
+            .bind::<Integer, _>(BLOB_LOCK_CLASS)
+            .bind::<Integer, _>(key)
+            .execute(conn)?;
+        Ok(BlobLock { conn, key })
+    }
+}
+
+impl Drop for BlobLock<'_> {
+    fn drop(&mut self) {
+        // If this fails the connection is broken, which releases the lock anyway.
+        let _ = sql_query("SELECT pg_advisory_unlock($1, $2)")
+            .bind::<Integer, _>(BLOB_LOCK_CLASS)
+            .bind::<Integer, _>(self.key)
+            .execute(self.conn);
+    }
+}
+
+fn blob_path(
+    hash: &str,
+    encoding: Option<Encoding>,
+    encrypted: bool,
+    upload_path: &Path,
+) -> PathBuf {
+    let mut file_name = hash.to_string();
+    if let Some(encoding) = encoding {
+        file_name = format!("{}.{}", file_name, encoding.extension());
+    }
+    if encrypted {
+        file_name.push_str(".enc");
+    }
+    upload_path.join(BLOBS_DIR).join(&hash[..2]).join(file_name)
+}
+
+fn thumbnail_path(hash: &str, size: u32, encrypted: bool, upload_path: &Path) -> PathBuf {
+    let mut file_name = format!("{}.thumb-{}.png", hash, size);
+    if encrypted {
+        file_name.push_str(".enc");
+    }
+    upload_path.join(BLOBS_DIR).join(&hash[..2]).join(file_name)
+}
+
+/// Read a thumbnail of a blob, if it has been made.
+pub fn read_thumbnail(hash: &str, size: u32, conf: &Config) -> io::Result<Vec<u8>> {
+    let mut contents = vec![];
+    let path = thumbnail_path(hash, size, true, &conf.upload_dir);
+    match File::open(&path) {
+        Ok(file) => {
+            DecryptReader::new(file, &conf.encryption.loaded_keys)?.read_to_end(&mut contents)?
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            File::open(thumbnail_path(hash, size, false, &conf.upload_dir))?
+                .read_to_end(&mut contents)?
+        }
+        Err(e) => return Err(e),
+    };
+    Ok(contents)
+}
+
+/// Store a thumbnail of a blob, encrypting it if a key is configured.
+///
+/// Nothing is stored if the blob has been deleted in the meantime.
+pub fn store_thumbnail(
+    hash: &str,
+    size: u32,
+    contents: &[u8],
+    conf: &Config,
+    conn: &PgConnection,
+) -> io::Result<()> {
+    let key = conf.encryption.current_key();
+    let temp = write_encrypted(&mut &contents[..], key, conf)?;
+    let _lock = BlobLock::take(hash, conn).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    if StoredBlob::find(hash, &conf.upload_dir).is_ok() {
+        temp.persist(&thumbnail_path(hash, size, key.is_some(), &conf.upload_dir))?;
+    }
+    Ok(())
+}
+
+/// Delete every thumbnail of a blob.
+fn remove_thumbnails(hash: &str, upload_path: &Path) -> io::Result<()> {
+    let dir = upload_path.join(BLOBS_DIR).join(&hash[..2]);
+    let prefix = format!("{}.thumb-", hash);
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with(&prefix) {
+            remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// A blob which has been stored, and how it was compressed and encrypted.
+pub struct StoredBlob {
+    pub path: PathBuf,
+    pub encoding: Option<Encoding>,
+    pub encrypted: bool,
+}
+
+impl StoredBlob {
+    /// Find the file a blob is stored in, however it is stored.
+    pub fn find(hash: &str, upload_path: &Path) -> io::Result<Self> {
+        let candidates = ENCODINGS.iter().copied().map(Some);
+        for encoding in std::iter::once(None).chain(candidates) {
+            for encrypted in [false, true] {
+                let path = blob_path(hash, encoding, encrypted, upload_path);
+                if path.exists() {
+                    return Ok(StoredBlob {
+                        path,
+                        encoding,
+                        encrypted,
+                    });
+                }
+            }
+        }
+        Err(io::ErrorKind::NotFound.into())
+    }
+
+    /// Open the blob for reading, decrypting it but not decompressing it.
+    pub fn open_encoded(&self, conf: &Config) -> io::Result<Box<dyn Read>> {
+        let file = File::open(&self.path)?;
+        match self.encrypted {
+            true => Ok(Box::new(DecryptReader::new(
+                file,
+                &conf.encryption.loaded_keys,
+            )?)),
+            false => Ok(Box::new(file)),
+        }
+    }
+
+    /// Open the blob for reading, decrypting and decompressing it if needed.
+    pub fn open(&self, conf: &Config) -> io::Result<Box<dyn Read>> {
+        let stream = self.open_encoded(conf)?;
+        match self.encoding {
+            Some(encoding) => encoding.decode(stream),
+            None => Ok(stream),
+        }
+    }
+}
+
+/// Open the original contents of a blob.
+pub fn open_blob(hash: &str, conf: &Config) -> io::Result<Box<dyn Read>> {
+    StoredBlob::find(hash, &conf.upload_dir)?.open(conf)
+}
+
+/// Read the original contents of a blob as a string.
+pub fn read_blob_to_string(hash: &str, conf: &Config) -> io::Result<String> {
+    let mut contents = String::new();
+    open_blob(hash, conf)?.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+/// Contents which have been written to a temporary file, but not stored.
+pub struct NewBlob {
+    pub hash: String,
+    pub size: u64,
+    file: TempFile,
+}
+
+impl NewBlob {
+    pub fn open(self) -> io::Result<TempFileReader> { self.file.open() }
+
+    pub fn path(&self) -> &Path { self.file.path() }
+}
+
+/// Writes contents to a temporary file, hashing them as they are written.
+pub struct BlobWriter {
+    file: TempFile,
+    out_stream: BufWriter<File>,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl BlobWriter {
+    pub fn create(conf: &Config) -> io::Result<Self> {
+        let (file, out_file) = TempFile::create(conf)?;
+        Ok(BlobWriter {
+            file,
+            out_stream: BufWriter::new(out_file),
+            hasher: Sha256::new(),
+            size: 0,
+        })
+    }
+
+    pub fn finish(mut self) -> io::Result<NewBlob> {
+        self.out_stream.flush()?;
+        Ok(NewBlob {
+            hash: format!("{:x}", self.hasher.finalize()),
+            size: self.size,
+            file: self.file,
+        })
+    }
+}
+
+impl Write for BlobWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.out_stream.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> { self.out_stream.flush() }
+}
+
+fn database_error<E>(_: E) -> status::Custom<String> {
+    status::Custom(Status::InternalServerError, "Database error.".into())
+}
+
+/// Compress a file, giving the compressed file if it is any smaller.
+fn compress_file(
+    file: &TempFile,
+    size: u64,
+    encoding: Encoding,
+    conf: &Config,
+) -> io::Result<Option<TempFile>> {
+    let (compressed, out_file) = TempFile::create(conf)?;
+    let out_file = encoding
+        .encode(
+            &mut File::open(file.path())?,
+            BufWriter::new(out_file),
+            conf.compression.level,
+        )?
+        .into_inner()
+        .map_err(|e| e.into_error())?;
+    match out_file.metadata()?.len() < size {
+        true => Ok(Some(compressed)),
+        false => Ok(None),
+    }
+}
+
+/// Write contents to a new temporary file, encrypting them if given a key.
+fn write_encrypted(
+    source: &mut impl Read,
+    key: Option<(&str, &Key)>,
+    conf: &Config,
+) -> io::Result<TempFile> {
+    let (temp, out_file) = TempFile::create(conf)?;
+    let mut out_stream = BufWriter::new(out_file);
+    match key {
+        Some((key_id, key)) => {
+            encrypt(source, &mut out_stream, key_id, key)?;
+        }
+        None => {
+            copy(source, &mut out_stream)?;
+        }
+    }
+    out_stream.flush()?;
+    Ok(temp)
+}
+
+/// Store new contents, or add a reference to them if they are already stored.
+///
+/// The contents are compressed if so configured for the kind of share they
+/// belong to, and encrypted if a key is configured. Returns the hash of the
+/// blob.
+/// New contents, compressed and encrypted ready to be stored.
+struct PreparedFile<'a> {
+    file: TempFile,
+    encoding: Option<Encoding>,
+    key_id: Option<&'a str>,
+}
+
+/// Compress and encrypt new contents, as configured.
+fn prepare_file(
+    mut file: TempFile,
+    size: u64,
+    kind: ShareKind,
+    conf: &Config,
+) -> Result<PreparedFile, status::Custom<String>> {
+    let mut encoding = None;
+    let mut key_id = None;
+    if let Some(wanted) = conf.compression.choose(kind, size) {
+        let compressed = compress_file(&file, size, wanted, conf).map_err(|_| {
+            status::Custom(
+                Status::InternalServerError,
+                "Could not compress file.".into(),
+            )
+        })?;
+        if let Some(compressed) = compressed {
+            file = compressed;
+            encoding = Some(wanted);
+        }
+    }
+    if let Some(key) = conf.encryption.current_key() {
+        file = File::open(file.path())
+            .and_then(|mut source| write_encrypted(&mut source, Some(key), conf))
+            .map_err(|_| {
+                status::Custom(
+                    Status::InternalServerError,
+                    "Could not encrypt file.".into(),
+                )
+            })?;
+        key_id = Some(key.0);
+    }
+    Ok(PreparedFile {
+        file,
+        encoding,
+        key_id,
+    })
+}
+
+pub fn store_blob(
+    blob: NewBlob,
+    kind: ShareKind,
+    conf: &Config,
+    conn: &PgConnection,
+) -> Result<String, status::Custom<String>> {
+    let NewBlob { hash, size, file } = blob;
+    // Compress and encrypt before taking the lock, since it may take a while.
+    // Contents which are already stored don't need either, so they are kept
+    // as they are in case they're deleted before the lock is taken.
+    let pending = match StoredBlob::find(&hash, &conf.upload_dir) {
+        Ok(_) => Err(file),
+        Err(_) => Ok(prepare_file(file, size, kind, conf)?),
+    };
+    let _lock = BlobLock::take(&hash, conn).map_err(database_error)?;
+    let mut key_id = None;
+    if StoredBlob::find(&hash, &conf.upload_dir).is_err() {
+        let prepared = match pending {
+            Ok(prepared) => prepared,
+            Err(file) => prepare_file(file, size, kind, conf)?,
+        };
+        let path = blob_path(
+            &hash,
+            prepared.encoding,
+            prepared.key_id.is_some(),
+            &conf.upload_dir,
+        );
+        path.parent()
+            .map_or(Ok(()), create_dir_all)
+            .and_then(|_| prepared.file.persist(&path))
+            .map_err(|_| {
+                status::Custom(Status::InternalServerError, "Could not write file.".into())
+            })?;
+        key_id = prepared.key_id;
+    }
+    insert_into(blobs::table)
+        .values((
+            blobs::hash.eq(&hash),
+            blobs::size.eq(size as i64),
+            blobs::refs.eq(1),
+            blobs::key_id.eq(key_id),
+        ))
+        .on_conflict(blobs::hash)
+        .do_update()
+        .set(blobs::refs.eq(blobs::refs + 1))
+        .execute(conn)
+        .map_err(database_error)?;
+    Ok(hash)
+}
+
+/// Add a reference to a blob which is already stored.
+pub fn acquire_blob(hash: &str, conn: &PgConnection) -> Result<(), status::Custom<String>> {
+    let _lock = BlobLock::take(hash, conn).map_err(database_error)?;
+    diesel::update(blobs::table.find(hash))
+        .set(blobs::refs.eq(blobs::refs + 1))
+        .execute(conn)
+        .map_err(database_error)?;
+    Ok(())
+}
+
+/// Remove a reference to a blob, deleting it if nothing else uses it.
+pub fn release_blob(
+    hash: &str,
+    upload_path: &Path,
+    conn: &PgConnection,
+) -> Result<(), status::Custom<String>> {
+    let _lock = BlobLock::take(hash, conn).map_err(database_error)?;
+    let refs = diesel::update(blobs::table.find(hash))
+        .set(blobs::refs.eq(blobs::refs - 1))
+        .returning(blobs::refs)
+        .get_result::<i32>(conn);
+    match refs {
+        Ok(refs) if refs > 0 => return Ok(()),
+        Ok(_) => {}
+        Err(diesel::result::Error::NotFound) => return Ok(()),
+        Err(e) => return Err(database_error(e)),
+    }
+    diesel::delete(blobs::table.find(hash))
+        .execute(conn)
+        .map_err(database_error)?;
+    let removed = StoredBlob::find(hash, upload_path)
+        .and_then(|blob| remove_file(blob.path))
+        .and_then(|_| remove_thumbnails(hash, upload_path));
+    match removed {
+        Ok(_) => Ok(()),
+        Err(e) => match e.kind() {
+            io::ErrorKind::NotFound => Ok(()),
+            _ => Err(status::Custom(
+                Status::InternalServerError,
+                "Filesystem error.".into(),
+            )),
+        },
+    }
+}
+
+/// Move a file stored before blobs were used into blob storage.
+///
+/// Returns None if the file is missing.
+fn store_legacy_file(
+    path: &Path,
+    kind: ShareKind,
+    conf: &Config,
+    conn: &PgConnection,
+) -> Result<Option<String>, String> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Could not open {}: {}", path.display(), e)),
+    };
+    let blob = BlobWriter::create(conf)
+        .and_then(|mut writer| copy(&mut file, &mut writer).and_then(|_| writer.finish()))
+        .map_err(|e| format!("Could not copy {}: {}", path.display(), e))?;
+    let hash = store_blob(blob, kind, conf, conn).map_err(|e| e.1)?;
+    remove_file(path).map_err(|e| format!("Could not delete {}: {}", path.display(), e))?;
+    Ok(Some(hash))
+}
+
+/// Move the contents of shares, revisions and bundle members which were
+/// uploaded before blobs were used into blob storage.
+///
+/// Rows whose files are missing are left as they are, or deleted for
+/// revisions and bundle members since they would be unusable.
+pub fn migrate_legacy_files(conf: &Config, conn: &PgConnection) -> Result<(), String> {
+    let db_error = |e: diesel::result::Error| format!("Database error: {}", e);
+    let legacy_shares = shares::table
+        .filter(shares::kind.eq_any(vec![ShareKind::Paste, ShareKind::File]))
+        .filter(shares::blob.is_null())
+        .select((shares::name, shares::kind))
+        .load::<(String, ShareKind)>(conn)
+        .map_err(db_error)?;
+    for (name, kind) in legacy_shares {
+        match store_legacy_file(&conf.upload_dir.join(&name), kind, conf, conn)? {
+            Some(hash) => {
+                diesel::update(shares::table.find(&name))
+                    .set(shares::blob.eq(hash))
+                    .execute(conn)
+                    .map_err(db_error)?;
+            }
+            None => eprintln!("File for share {} is missing.", name),
+        }
+    }
+    let legacy_revisions = revisions::table
+        .inner_join(shares::table)
+        .filter(revisions::blob.eq(""))
+        .select((revisions::share_name, revisions::number, shares::kind))
+        .load::<(String, i32, ShareKind)>(conn)
+        .map_err(db_error)?;
+    for (name, number, kind) in legacy_revisions {
+        let path = conf
+            .upload_dir
+            .join(LEGACY_REVISIONS_DIR)
+            .join(&name)
+            .join(number.to_string());
+        let revision = revisions::table.find((&name, number));
+        let result = match store_legacy_file(&path, kind, conf, conn)? {
+            Some(hash) => diesel::update(revision)
+                .set(revisions::blob.eq(hash))
+                .execute(conn),
+            None => diesel::delete(revision).execute(conn),
+        };
+        result.map_err(db_error)?;
+    }
+    let legacy_members = bundle_members::table
+        .filter(bundle_members::blob.eq(""))
+        .select((bundle_members::share_name, bundle_members::position))
+        .load::<(String, i32)>(conn)
+        .map_err(db_error)?;
+    for (name, position) in legacy_members {
+        let path = conf
+            .upload_dir
+            .join(LEGACY_BUNDLES_DIR)
+            .join(&name)
+            .join(position.to_string());
+        let member = bundle_members::table.find((&name, position));
+        let result = match store_legacy_file(&path, ShareKind::Bundle, conf, conn)? {
+            Some(hash) => diesel::update(member)
+                .set(bundle_members::blob.eq(hash))
+                .execute(conn),
+            None => diesel::delete(member).execute(conn),
+        };
+        result.map_err(db_error)?;
+    }
+    Ok(())
+}
+
+/// Mark the database as in use by a server, for as long as the connection is
+/// open. Any number of servers may share it.
+///
+/// Returns false if a command which needs the servers stopped is running.
+pub fn lock_for_server(conn: &PgConnection) -> QueryResult<bool> {
+    diesel::select(pg_try_advisory_lock_shared(SERVER_LOCK_KEY)).get_result(conn)
+}
+
+/// Re-encrypt every blob which isn't encrypted with the current key, or
+/// decrypt them if no key is configured any more.
+///
+/// Servers may be reading the files while they are replaced, so this refuses
+/// to run while any server is using the database.
+///
+/// Returns the number of blobs changed.
+pub fn rotate_keys(conf: &Config, conn: &PgConnection) -> Result<usize, String> {
+    let db_error = |e: diesel::result::Error| format!("Database error: {}", e);
+    let locked = diesel::select(pg_try_advisory_lock(SERVER_LOCK_KEY))
+        .get_result::<bool>(conn)
+        .map_err(db_error)?;
+    if !locked {
+        return Err("The server is running, so it must be stopped first.".into());
+    }
+    let current = conf.encryption.current_key();
+    let current_id = current.map(|(key_id, _)| key_id);
+    let hashes = match current_id {
+        Some(key_id) => blobs::table
+            .filter(blobs::key_id.is_distinct_from(key_id))
+            .select(blobs::hash)
+            .load::<String>(conn),
+        None => blobs::table
+            .filter(blobs::key_id.is_not_null())
+            .select(blobs::hash)
+            .load::<String>(conn),
+    }
+    .map_err(db_error)?;
+    let mut count = 0;
+    for hash in hashes {
+        let stored = match StoredBlob::find(&hash, &conf.upload_dir) {
+            Ok(stored) => stored,
+            Err(_) => {
+                eprintln!("File for blob {} is missing.", hash);
+                continue;
+            }
+        };
+        let temp = stored
+            .open_encoded(conf)
+            .and_then(|mut source| write_encrypted(&mut source, current, conf))
+            .map_err(|e| format!("Could not re-encrypt blob {}: {}", hash, e))?;
+        let path = blob_path(&hash, stored.encoding, current.is_some(), &conf.upload_dir);
+        temp.persist(&path)
+            .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+        if path != stored.path {
+            remove_file(&stored.path)
+                .map_err(|e| format!("Could not delete {}: {}", stored.path.display(), e))?;
+        }
+        // Thumbnails are made again when they are next needed.
+        remove_thumbnails(&hash, &conf.upload_dir)
+            .map_err(|e| format!("Could not delete thumbnails of {}: {}", hash, e))?;
+        let updated = diesel::update(blobs::table.find(&hash))
+            .set(blobs::key_id.eq(current_id))
+            .execute(conn)
+            .map_err(db_error)?;
+        if updated == 0 {
+            // The blob was deleted by the server while we were working on it.
+            let _ = remove_file(&path);
+            continue;
+        }
+        count += 1;
+    }
+    Ok(count)
+}
@@ -0,0 +1,57 @@
+//! Tools for files which only need to be kept while handling a request.
+use crate::config::Config;
+use crate::names::get_upload_name;
+use std::fs::{create_dir_all, remove_file, rename, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Directory within the upload directory where temporary files are kept.
+///
+/// Share names cannot contain "@", so this can't clash with a share.
+const TEMP_DIR: &str = "@uploads";
+
+/// A file in the temporary directory, which is deleted when dropped.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Create an empty temporary file, returning it opened for writing.
+    pub fn create(conf: &Config) -> io::Result<(Self, File)> {
+        let dir = conf.upload_dir.join(TEMP_DIR);
+        create_dir_all(&dir)?;
+        let path = dir.join(get_upload_name());
+        let file = File::create(&path)?;
+        Ok((TempFile { path }, file))
+    }
+
+    pub fn path(&self) -> &Path { &self.path }
+
+    pub fn open(self) -> io::Result<TempFileReader> {
+        Ok(TempFileReader {
+            file: File::open(&self.path)?,
+            _temp: self,
+        })
+    }
+
+    /// Move the file to a permanent location.
+    pub fn persist(self, path: &Path) -> io::Result<()> { rename(&self.path, path) }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // This will fail if the file has been persisted, which is fine.
+        let _ = remove_file(&self.path);
+    }
+}
+
+/// Reads a temporary file, deleting it once done.
+pub struct TempFileReader {
+    // Fields are dropped in order, so the file is closed before it is deleted.
+    file: File,
+    _temp: TempFile,
+}
+
+impl Read for TempFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.file.read(buf) }
+}
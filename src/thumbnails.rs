@@ -0,0 +1,158 @@
+//! Manages a background worker to make thumbnails of uploaded images.
+use crate::config::Config;
+use crate::mime::essence;
+use crate::storage::{open_blob, read_thumbnail, store_thumbnail};
+use diesel::{Connection, PgConnection};
+use image::io::{Limits, Reader};
+use image::ImageOutputFormat;
+use rocket::http::Status;
+use rocket::response::status;
+use std::collections::HashSet;
+use std::io::{Cursor, Read};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// The MIME types of images which thumbnails can be made of.
+const IMAGE_MIME_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/bmp",
+];
+
+/// Decoded images take up to this many bytes for each pixel.
+const BYTES_PER_PIXEL: u64 = 4;
+
+/// Whether thumbnails can be made of files of a type.
+pub fn is_image(mime_type: &str) -> bool { IMAGE_MIME_TYPES.contains(&essence(mime_type).as_str()) }
+
+/// Make thumbnails of a blob at every configured size.
+fn make_thumbnails(hash: &str, conf: &Config, conn: &PgConnection) -> Result<(), String> {
+    let mut contents = vec![];
+    open_blob(hash, conf)
+        .and_then(|mut source| source.read_to_end(&mut contents))
+        .map_err(|e| format!("Could not read blob: {}", e))?;
+    let max_decoded_size = conf.thumbnails.max_decoded_size(&conf.restrictions);
+    // Check the size given in the header before decoding anything, since the
+    // decoders don't all respect the allocation limit.
+    let (width, height) = Reader::new(Cursor::new(&contents))
+        .with_guessed_format()
+        .map_err(|e| e.to_string())?
+        .into_dimensions()
+        .map_err(|e| e.to_string())?;
+    if u64::from(width) * u64::from(height) * BYTES_PER_PIXEL > max_decoded_size {
+        return Err(format!("Image is too large ({}x{}).", width, height));
+    }
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(max_decoded_size);
+    let mut reader = Reader::new(Cursor::new(&contents))
+        .with_guessed_format()
+        .map_err(|e| e.to_string())?;
+    reader.limits(limits);
+    let image = reader.decode().map_err(|e| e.to_string())?;
+    for &size in &conf.thumbnails.sizes {
+        let mut png = Cursor::new(vec![]);
+        image
+            .thumbnail(size, size)
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .map_err(|e| e.to_string())?;
+        store_thumbnail(hash, size, png.get_ref(), conf, conn)
+            .map_err(|e| format!("Could not store thumbnail: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Blobs which the background worker has been given, or couldn't manage.
+#[derive(Default)]
+struct Jobs {
+    queued: HashSet<String>,
+    /// Blobs which thumbnails couldn't be made of, so that they aren't tried
+    /// again on every request. This is forgotten when the server restarts.
+    failed: HashSet<String>,
+}
+
+/// Queues blobs to have thumbnails made by the background worker.
+pub struct ThumbnailQueue {
+    sender: Mutex<Sender<String>>,
+    jobs: Arc<Mutex<Jobs>>,
+}
+
+impl ThumbnailQueue {
+    /// Queue thumbnails to be made of a file, if it is an image.
+    pub fn push(&self, hash: &str, mime_type: &str, conf: &Config) {
+        if conf.thumbnails.sizes.is_empty() || !is_image(mime_type) {
+            return;
+        }
+        self.queue(hash);
+    }
+
+    fn queue(&self, hash: &str) {
+        // The lock protects no invariants, so it doesn't matter if it was poisoned.
+        let mut jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        // New contents with the same hash can't be decoded any better.
+        if jobs.failed.contains(hash) || !jobs.queued.insert(hash.to_string()) {
+            return;
+        }
+        if let Ok(sender) = self.sender.lock() {
+            // This only fails if the worker has stopped, which it never does.
+            let _ = sender.send(hash.to_string());
+        }
+    }
+}
+
+pub fn start_thumbnail_worker(conf: &Config) -> ThumbnailQueue {
+    let (sender, receiver) = channel::<String>();
+    let jobs = Arc::new(Mutex::new(Jobs::default()));
+    let worker_jobs = jobs.clone();
+    let conf = conf.clone();
+    thread::spawn(move || {
+        let conn = PgConnection::establish(&conf.make_database_url())
+            .expect("Failed to connect to database");
+        for hash in receiver {
+            let result = make_thumbnails(&hash, &conf, &conn);
+            let mut jobs = worker_jobs.lock().unwrap_or_else(|e| e.into_inner());
+            jobs.queued.remove(&hash);
+            if let Err(e) = result {
+                eprintln!("Could not make thumbnails of blob {}: {}", hash, e);
+                jobs.failed.insert(hash);
+            }
+        }
+    });
+    ThumbnailQueue {
+        sender: Mutex::new(sender),
+        jobs,
+    }
+}
+
+/// Get a thumbnail of a blob, queueing it to be made if it hasn't been yet.
+///
+/// Thumbnails may be missing if the image was uploaded before thumbnails were
+/// configured, or if the background worker hasn't got to it yet. Either way,
+/// a `404` error is returned until the worker has made it.
+pub fn get_thumbnail(
+    hash: &str,
+    size: u32,
+    queue: &ThumbnailQueue,
+    conf: &Config,
+) -> Result<Vec<u8>, status::Custom<String>> {
+    if let Ok(thumbnail) = read_thumbnail(hash, size, conf) {
+        return Ok(thumbnail);
+    }
+    let failed = {
+        let jobs = queue.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        jobs.failed.contains(hash)
+    };
+    if failed {
+        return Err(status::Custom(
+            Status::UnprocessableEntity,
+            "Could not make a thumbnail of this image.".into(),
+        ));
+    }
+    queue.queue(hash);
+    Err(status::Custom(
+        Status::NotFound,
+        "Thumbnail is not ready yet.".into(),
+    ))
+}
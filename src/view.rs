@@ -0,0 +1,79 @@
+//! Tools for deciding how a share should be presented to the client.
+use crate::compression::Encoding;
+use crate::config::Config;
+use crate::headers::HeaderParams;
+use crate::render::Renderer;
+use rocket::http::Status;
+use rocket::response::status;
+
+/// Query parameters accepted when getting a share.
+#[derive(FromForm, Default)]
+pub struct ViewQuery {
+    pub render: Option<String>,
+    pub theme: Option<String>,
+    /// Present (with any value) to get the original contents of a paste.
+    pub raw: Option<String>,
+    /// The password of a protected share, for browsers which can't set headers.
+    pub password: Option<String>,
+    /// Present (with any value) to show a preview page for a link.
+    pub preview: Option<String>,
+}
+
+/// Options from the request which affect how a share body is presented.
+pub struct ViewOptions {
+    pub accept_redirect: bool,
+    pub render_html: bool,
+    pub theme: String,
+    pub accept_encodings: Vec<Encoding>,
+    /// The password of a protected share, if given in the query, so that it
+    /// can be kept in links to the share.
+    pub query_password: Option<String>,
+    /// Show browsers a preview page for a link, even if it wasn't created with
+    /// one.
+    pub preview: bool,
+}
+
+impl ViewOptions {
+    fn parse_render(raw: Option<&str>, accept_html: bool) -> Result<bool, status::Custom<String>> {
+        match raw {
+            Some("html") => Ok(true),
+            Some("plain") => Ok(false),
+            Some(_) => Err(status::Custom(
+                Status::BadRequest,
+                "render must be html or plain.".into(),
+            )),
+            None => Ok(accept_html),
+        }
+    }
+
+    pub fn new(
+        query: &ViewQuery,
+        headers: &HeaderParams,
+        conf: &Config,
+        renderer: &Renderer,
+    ) -> Result<Self, status::Custom<String>> {
+        let render_html = Self::parse_render(query.render.as_deref(), headers.accept_html)?
+            && query.raw.is_none();
+        let theme = match &query.theme {
+            Some(theme) => {
+                if renderer.has_theme(theme) {
+                    theme.clone()
+                } else {
+                    return Err(status::Custom(
+                        Status::BadRequest,
+                        "Unknown highlighting theme.".into(),
+                    ));
+                }
+            }
+            None => conf.rendering.default_theme.clone(),
+        };
+        Ok(ViewOptions {
+            accept_redirect: headers.accept_redirect,
+            render_html,
+            theme,
+            accept_encodings: headers.accept_encodings.clone(),
+            query_password: query.password.clone(),
+            preview: query.preview.is_some(),
+        })
+    }
+}